@@ -1,52 +1,130 @@
 use std::sync::Arc;
-use std::time::Duration;
-use tokio::sync::RwLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::{RwLock, mpsc};
 use tokio::task::JoinHandle;
 use tokio::time::sleep;
+use wmidi::{MidiMessage, Note};
 
-use crate::state::{GlobalState, Perform};
+use crate::state::{ArpeggioDirection, GlobalState, Perform};
 
-fn create(state: Arc<RwLock<GlobalState>>) -> JoinHandle<()> {
+pub fn run(state: Arc<RwLock<GlobalState>>, tx: mpsc::Sender<Vec<u8>>) -> JoinHandle<()> {
     tokio::spawn(async move {
         println!("Arpeggiator running");
-        let state_readable = state.clone();
-        let state_writable = state;
+        let mut rng = XorShift::new();
 
         loop {
-            let state_read = state_readable.read().await;
-            match state_read.perform {
-                Perform::Arpeggio => {
-                    let index = state_read.perform_params.arpeggiator.index;
-                    if let Some(note) = state_read
-                        .active_notes
-                        .get(index % state_read.active_notes.len())
-                    {
-                        // play note
-
-                        let seconds_per_minute = 60.0;
-                        let ms_per_second = 1000.0;
-                        let beats_per_bar = 4.0;
-
-                        // block to automatically drop state_write
-                        {
-                            // Update index for next note
-                            let mut state_write = state_writable.write().await;
-                            // do not modulo index because notes may be added or removed via modifiers
-                            state_write.perform_params.arpeggiator.index = index + 1;
-                        }
-                        // Wait for next note duration
-                        let wait_ms = (beats_per_bar * ms_per_second * seconds_per_minute
-                            / state_read.bpm)
-                            / f32::from(state_read.perform_params.arpeggiator.rate as u8);
-                        drop(state_read);
-                        sleep(Duration::from_millis(wait_ms as u64)).await;
-                    }
+            // block to automatically drop the state lock before sleeping
+            let step = {
+                let mut state = state.write().await;
+                let two_octaves = state
+                    .perform
+                    .is_arpeggio()
+                    .then_some(state.perform == Perform::Arpeggio2Octave);
+                let arpeggiator = state.perform_params.arpeggiator;
+                let sequence = two_octaves
+                    .map(|two_octaves| {
+                        get_sequence(&state.active_notes, arpeggiator.direction, two_octaves)
+                    })
+                    .unwrap_or_default();
+                if sequence.is_empty() {
+                    // start from the first note of the next chord
+                    state.perform_params.arpeggiator.index = 0;
+                    None
+                } else {
+                    let note = match arpeggiator.direction {
+                        ArpeggioDirection::Random => sequence[rng.next() % sequence.len()],
+                        // do not modulo index because notes may be added or removed via modifiers
+                        _ => sequence[arpeggiator.index % sequence.len()],
+                    };
+                    state.perform_params.arpeggiator.index = arpeggiator.index + 1;
+
+                    let seconds_per_minute = 60.0;
+                    let ms_per_second = 1000.0;
+                    let beats_per_bar = 4.0;
+                    let step_ms = (beats_per_bar * ms_per_second * seconds_per_minute / state.bpm)
+                        / f32::from(arpeggiator.rate as u8);
+                    let gate_ms = step_ms * f32::from(arpeggiator.gate.min(100)) / 100.0;
+                    Some((
+                        note,
+                        arpeggiator,
+                        Duration::from_millis(gate_ms as u64),
+                        Duration::from_millis((step_ms - gate_ms) as u64),
+                    ))
                 }
-                _ => {
-                    drop(state_read);
-                    sleep(Duration::from_millis(10)).await;
+            };
+
+            match step {
+                Some((note, arpeggiator, gate, rest)) => {
+                    send(
+                        &tx,
+                        MidiMessage::NoteOn(arpeggiator.channel, note, arpeggiator.velocity),
+                    )
+                    .await;
+                    sleep(gate).await;
+                    send(
+                        &tx,
+                        MidiMessage::NoteOff(arpeggiator.channel, note, arpeggiator.velocity),
+                    )
+                    .await;
+                    sleep(rest).await;
                 }
+                None => sleep(Duration::from_millis(10)).await,
             }
         }
     })
 }
+
+async fn send(tx: &mpsc::Sender<Vec<u8>>, message: MidiMessage<'_>) {
+    if let Err(e) = tx.send(message.to_vec()).await {
+        println!("Failed to send MIDI message: {:?}", e);
+    }
+}
+
+/// Get the notes of one arpeggio cycle. `notes` are expected in the order they were played.
+fn get_sequence(notes: &[Note], direction: ArpeggioDirection, two_octaves: bool) -> Vec<Note> {
+    let mut sequence = notes.to_vec();
+    if direction != ArpeggioDirection::AsPlayed {
+        sequence.sort();
+        sequence.dedup();
+    }
+    if two_octaves {
+        let octave_up: Vec<Note> = sequence.iter().filter_map(|n| n.step(12).ok()).collect();
+        sequence.extend(octave_up);
+    }
+    match direction {
+        ArpeggioDirection::Down => sequence.reverse(),
+        ArpeggioDirection::UpDown => {
+            // don't repeat the top and bottom notes when changing direction
+            let down: Vec<Note> = sequence
+                .iter()
+                .rev()
+                .skip(1)
+                .take(sequence.len().saturating_sub(2))
+                .copied()
+                .collect();
+            sequence.extend(down);
+        }
+        ArpeggioDirection::Up | ArpeggioDirection::Random | ArpeggioDirection::AsPlayed => (),
+    }
+    sequence
+}
+
+/// Minimal xorshift generator for random arpeggios; doesn't need to be high quality
+struct XorShift(u64);
+
+impl XorShift {
+    fn new() -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64);
+        // the state must never be zero
+        Self(seed | 1)
+    }
+
+    fn next(&mut self) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 as usize
+    }
+}
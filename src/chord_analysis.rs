@@ -0,0 +1,248 @@
+use crate::chord_name::get_chord_name;
+use crate::modifier::{Extension, Inversion, Quality};
+use wmidi::Note;
+
+/// A chord that a set of notes can be read as
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChordCandidate {
+    pub root: Note,
+    pub quality: Option<Quality>,
+    pub extensions: Vec<Extension>,
+    pub inversion: Inversion,
+    pub symbol: String,
+    /// How unusual the reading is; lower scores are more likely
+    pub score: u32,
+}
+
+/// Get the chords the notes could be, most likely first. Every pitch class is tried as the root,
+/// and readings with common qualities, few extensions and the root in the bass are preferred.
+pub fn analyze(notes: &[Note]) -> Vec<ChordCandidate> {
+    let Some(&bass) = notes.iter().min() else {
+        return vec![];
+    };
+    let mut roots: Vec<Note> = notes.to_vec();
+    roots.sort();
+    roots.dedup_by_key(|n| u8::from(*n) % 12);
+
+    let mut candidates: Vec<ChordCandidate> = roots
+        .into_iter()
+        .map(|root| analyze_root(root, bass, notes))
+        .collect();
+    candidates.sort_by_key(|c| (c.score, u8::from(c.root)));
+    candidates
+}
+
+/// Read the notes as a chord built on `root`
+fn analyze_root(root: Note, bass: Note, notes: &[Note]) -> ChordCandidate {
+    let interval = |note: Note| (u8::from(note) + 12 - u8::from(root) % 12) % 12;
+    let mut intervals = [false; 12];
+    for note in notes {
+        intervals[usize::from(interval(*note))] = true;
+    }
+    let has = |semitones: usize| intervals[semitones];
+
+    let mut score = 0;
+    // the triad, with the tones it accounts for
+    let (quality, third, fifth) = if has(4) && has(7) {
+        (Some(Quality::Major), Some(4), Some(7))
+    } else if has(3) && has(7) {
+        (Some(Quality::Minor), Some(3), Some(7))
+    } else if has(3) && has(6) {
+        (Some(Quality::Diminished), Some(3), Some(6))
+    } else if has(4) && has(8) {
+        (Some(Quality::Augmented), Some(4), Some(8))
+    } else if has(5) && has(7) {
+        (Some(Quality::Sus4), Some(5), Some(7))
+    } else if has(2) && has(7) {
+        (Some(Quality::Sus2), Some(2), Some(7))
+    } else if has(4) {
+        // an incomplete triad, missing its fifth
+        score += 2;
+        (Some(Quality::Major), Some(4), None)
+    } else if has(3) {
+        score += 2;
+        (Some(Quality::Minor), Some(3), None)
+    } else {
+        score += 6;
+        (None, None, has(7).then_some(7))
+    };
+    let used =
+        |semitones: usize| semitones == 0 || Some(semitones) == third || Some(semitones) == fifth;
+
+    let seventh = [11, 10].into_iter().find(|&s| has(s));
+    // a diminished seventh is a diminished triad with a sixth
+    let diminished_seventh = quality == Some(Quality::Diminished) && seventh.is_none() && has(9);
+    let mut extensions = Vec::new();
+    let mut push = |extension: Extension, cost: u32| {
+        extensions.push(extension);
+        score += cost;
+    };
+    match seventh {
+        Some(11) => push(Extension::MajorSeventh, 1),
+        Some(_) => push(Extension::MinorSeventh, 1),
+        None => (),
+    }
+    for semitones in (1..12).filter(|&s| has(s) && !used(s) && Some(s) != seventh) {
+        match (semitones, seventh.is_some()) {
+            (9, _) if diminished_seventh => push(Extension::Sixth, 1),
+            (9, false) if has(2) && third != Some(2) => push(Extension::SixNine, 2),
+            (2, false) if has(9) => (),
+            (1, _) => push(Extension::FlatNinth, 3),
+            (2, true) => push(Extension::Ninth, 2),
+            (2, false) => push(Extension::Add9, 2),
+            (3, _) => push(Extension::SharpNinth, 3),
+            (4, _) => push(Extension::FlatEleventh, 4),
+            (5, true) => push(Extension::Eleventh, 2),
+            (5, false) => push(Extension::Add11, 2),
+            (6, _) => push(Extension::SharpEleventh, 3),
+            (8, true) => push(Extension::FlatThirteenth, 3),
+            (8, false) => push(Extension::FlatSixth, 3),
+            (9, true) => push(Extension::Thirteenth, 2),
+            (9, false) => push(Extension::Sixth, 1),
+            _ => (),
+        }
+    }
+
+    let inversion = match interval(bass) {
+        0 => Inversion::Root,
+        b if Some(usize::from(b)) == third => Inversion::First,
+        b if Some(usize::from(b)) == fifth => Inversion::Second,
+        b if Some(usize::from(b)) == seventh || (diminished_seventh && b == 9) => Inversion::Third,
+        // a tension in the bass is read as a slash chord over the root position
+        _ => {
+            score += 2;
+            Inversion::Root
+        }
+    };
+    if inversion != Inversion::Root {
+        score += 1;
+    }
+
+    ChordCandidate {
+        root,
+        quality,
+        extensions,
+        inversion,
+        symbol: get_chord_name(root, notes),
+        score,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modifier::{Modifier, ModifierStack, RangePolicy};
+    use crate::voicing::Voicing;
+
+    /// Build a chord on middle C and check the analyzer ranks the same reading first
+    fn round_trip(
+        quality: Quality,
+        held: &[Extension],
+        inversion: Inversion,
+        expected: &[Extension],
+    ) {
+        let mut stack = ModifierStack::new();
+        stack.update(Modifier::Quality(quality), true);
+        for &extension in held {
+            stack.update(Modifier::Extension(extension), true);
+        }
+        stack.update(Modifier::Inversion(inversion), true);
+        let notes = stack.get_notes(Note::C4, Voicing::Close, RangePolicy::Fold);
+
+        let best = &analyze(&notes)[0];
+        assert_eq!(u8::from(best.root) % 12, 0, "{:?}", best);
+        assert_eq!(best.quality, Some(quality), "{:?}", best);
+        assert_eq!(best.inversion, inversion, "{:?}", best);
+        assert_eq!(best.extensions.len(), expected.len(), "{:?}", best);
+        assert!(
+            expected.iter().all(|e| best.extensions.contains(e)),
+            "{:?}",
+            best
+        );
+    }
+
+    #[test]
+    fn reads_triads() {
+        round_trip(Quality::Major, &[], Inversion::Root, &[]);
+        round_trip(Quality::Minor, &[], Inversion::First, &[]);
+        round_trip(Quality::Diminished, &[], Inversion::Root, &[]);
+        round_trip(Quality::Sus4, &[], Inversion::Root, &[]);
+    }
+
+    #[test]
+    fn reads_seventh_chords() {
+        use Extension::*;
+        round_trip(
+            Quality::Minor,
+            &[MinorSeventh],
+            Inversion::Root,
+            &[MinorSeventh],
+        );
+        round_trip(
+            Quality::Major,
+            &[MajorSeventh],
+            Inversion::Second,
+            &[MajorSeventh],
+        );
+        round_trip(
+            Quality::Diminished,
+            &[MinorSeventh],
+            Inversion::Root,
+            &[MinorSeventh],
+        );
+        round_trip(
+            Quality::Sus4,
+            &[MinorSeventh],
+            Inversion::Root,
+            &[MinorSeventh],
+        );
+        round_trip(
+            Quality::Major,
+            &[MinorSeventh],
+            Inversion::Third,
+            &[MinorSeventh],
+        );
+    }
+
+    #[test]
+    fn reads_extended_chords() {
+        use Extension::*;
+        round_trip(
+            Quality::Major,
+            &[Ninth],
+            Inversion::Root,
+            &[MinorSeventh, Ninth],
+        );
+        round_trip(
+            Quality::Major,
+            &[SharpNinth],
+            Inversion::Root,
+            &[MinorSeventh, SharpNinth],
+        );
+        round_trip(
+            Quality::Minor,
+            &[MinorSeventh, Eleventh],
+            Inversion::Root,
+            &[MinorSeventh, Eleventh],
+        );
+        round_trip(
+            Quality::Major,
+            &[MajorSeventh, SharpEleventh],
+            Inversion::Root,
+            &[MajorSeventh, SharpEleventh],
+        );
+        round_trip(Quality::Major, &[Add9], Inversion::Root, &[Add9]);
+        round_trip(Quality::Major, &[SixNine], Inversion::Root, &[SixNine]);
+    }
+
+    #[test]
+    fn counts_the_suspended_second_once() {
+        // C D G A is a sixth chord with a suspended second, not a six-nine
+        round_trip(
+            Quality::Sus2,
+            &[Extension::Sixth],
+            Inversion::Root,
+            &[Extension::Sixth],
+        );
+    }
+}
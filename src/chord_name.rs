@@ -0,0 +1,209 @@
+use wmidi::Note;
+
+/// Names of the pitch classes, spelled the way they're most often written in chord symbols
+const PITCH_CLASS_NAMES: [&str; 12] = [
+    "C", "Db", "D", "Eb", "E", "F", "F#", "G", "Ab", "A", "Bb", "B",
+];
+
+/// Get the name of a note's pitch class, e.g. "F#"
+pub fn get_pitch_class_name(note: Note) -> &'static str {
+    PITCH_CLASS_NAMES[usize::from(u8::from(note) % 12)]
+}
+
+/// Get the chord symbol for `notes` built on `root`, e.g. "Dm7/F" or "G7#9b13". The symbol
+/// describes the pitch classes present, and names the lowest note after a slash if it isn't the
+/// root.
+pub fn get_chord_name(root: Note, notes: &[Note]) -> String {
+    let root_class = u8::from(root) % 12;
+    let mut intervals = [false; 12];
+    for note in notes {
+        intervals[usize::from((u8::from(*note) + 12 - root_class) % 12)] = true;
+    }
+    let has = |semitones: usize| intervals[semitones];
+
+    let major_third = has(4);
+    let minor_third = has(3) && !major_third;
+    let seventh = if has(11) {
+        Some(11)
+    } else if has(10) {
+        Some(10)
+    } else {
+        None
+    };
+    let diminished = minor_third && has(6) && !has(7);
+    let augmented = major_third && has(8) && !has(7);
+
+    let mut symbol = String::from(get_pitch_class_name(root));
+    // suspensions are written after the seventh, e.g. "C7sus4"
+    let mut suspension = "";
+    // the quality of the triad, and the tones it accounts for
+    let (quality, mut used) = if diminished && seventh.is_none() && has(9) {
+        ("dim7", vec![3, 6, 9])
+    } else if diminished && seventh == Some(10) {
+        ("m7b5", vec![3, 6, 10])
+    } else if diminished {
+        ("dim", vec![3, 6])
+    } else if augmented {
+        ("aug", vec![4, 8])
+    } else if minor_third {
+        ("m", vec![3])
+    } else if major_third {
+        ("", vec![4])
+    } else if has(5) {
+        suspension = "sus4";
+        ("", vec![5])
+    } else if has(2) {
+        suspension = "sus2";
+        ("", vec![2])
+    } else if has(7) && (1..12).all(|semitones| semitones == 7 || !has(semitones)) {
+        ("5", vec![])
+    } else {
+        ("", vec![])
+    };
+    let no_third = quality.is_empty() && suspension.is_empty() && !major_third && has(7);
+    used.push(0);
+    used.push(7);
+    symbol.push_str(quality);
+
+    // the seventh, raised to the highest natural extension it carries
+    match seventh {
+        // already part of the quality, e.g. "m7b5"
+        Some(seventh) if used.contains(&seventh) => (),
+        Some(seventh) => {
+            used.push(seventh);
+            let extension = if has(9) {
+                used.push(9);
+                "13"
+            } else if has(5) && !used.contains(&5) {
+                used.push(5);
+                "11"
+            } else if has(2) && !used.contains(&2) {
+                used.push(2);
+                "9"
+            } else {
+                "7"
+            };
+            if seventh == 11 {
+                // a major seventh over a minor triad is written in parentheses to keep it apart
+                // from the quality
+                symbol.push_str(if minor_third { "(maj" } else { "maj" });
+                symbol.push_str(extension);
+                if minor_third {
+                    symbol.push(')');
+                }
+            } else {
+                symbol.push_str(extension);
+            }
+            // lower natural extensions are implied by the higher ones
+            if extension == "13" || extension == "11" {
+                for natural in [2, 5] {
+                    if has(natural) && !used.contains(&natural) && !(natural == 5 && major_third) {
+                        used.push(natural);
+                    }
+                }
+            }
+        }
+        None => {
+            if has(9) && !used.contains(&9) {
+                used.push(9);
+                if has(2) && !used.contains(&2) {
+                    used.push(2);
+                    symbol.push_str("6/9");
+                } else {
+                    symbol.push('6');
+                }
+            }
+        }
+    }
+
+    // alterations, in order of the degree they alter
+    symbol.push_str(suspension);
+    let perfect_fifth = has(7);
+    // without a seventh, a minor sixth isn't heard as a tension, e.g. "Cm(b6)"
+    let flat_sixth = if seventh.is_some() { "b13" } else { "(b6)" };
+    let alterations = [
+        (6, if perfect_fifth { "#11" } else { "b5" }),
+        (8, if perfect_fifth { flat_sixth } else { "#5" }),
+        (1, "b9"),
+        (3, "#9"),
+        // natural tensions the chord couldn't be raised to
+        (5, "add11"),
+        (2, "add9"),
+    ];
+    let mut altered: Vec<(usize, &str)> = alterations
+        .iter()
+        .filter(|(semitones, _)| has(*semitones) && !used.contains(semitones))
+        .map(|&(_, name)| (degree(name), name))
+        .collect();
+    altered.sort_by_key(|(degree, _)| *degree);
+    for (_, name) in altered {
+        symbol.push_str(name);
+    }
+    // other tones over a bare fifth, e.g. "C7(no3)"
+    if no_third {
+        symbol.push_str("(no3)");
+    }
+
+    if let Some(bass) = notes.iter().min()
+        && u8::from(*bass) % 12 != root_class
+    {
+        symbol.push('/');
+        symbol.push_str(get_pitch_class_name(*bass));
+    }
+    symbol
+}
+
+/// Get the scale degree a tension or alteration is written as, for ordering
+fn degree(name: &str) -> usize {
+    name.trim_matches(['(', ')'])
+        .trim_start_matches(['b', '#', 'a', 'd'])
+        .parse()
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(root: u8, notes: &[u8]) -> String {
+        let notes: Vec<Note> = notes.iter().map(|&n| Note::from_u8_lossy(n)).collect();
+        get_chord_name(Note::from_u8_lossy(root), &notes)
+    }
+
+    #[test]
+    fn names_seventh_chords() {
+        // F A C D
+        assert_eq!(name(62, &[53, 57, 60, 62]), "Dm7/F");
+        // G B D F A# D#
+        assert_eq!(name(55, &[55, 59, 62, 65, 70, 75]), "G7#9b13");
+        // C E G B F#
+        assert_eq!(name(60, &[60, 64, 67, 71, 78]), "Cmaj7#11");
+        // C E G Bb D#
+        assert_eq!(name(60, &[60, 64, 67, 70, 75]), "C7#9");
+        // C Eb G B
+        assert_eq!(name(60, &[60, 63, 67, 71]), "Cm(maj7)");
+        // C F G Bb
+        assert_eq!(name(60, &[60, 65, 67, 70]), "C7sus4");
+    }
+
+    #[test]
+    fn names_a_flat_sixth_without_a_seventh() {
+        // C E G Ab
+        assert_eq!(name(60, &[60, 64, 67, 68]), "C(b6)");
+        // C Eb G Ab
+        assert_eq!(name(60, &[60, 63, 67, 68]), "Cm(b6)");
+        // C Eb G Ab D
+        assert_eq!(name(60, &[60, 63, 67, 68, 74]), "Cm(b6)add9");
+        // with a seventh it's still a thirteenth: C E G Bb Ab
+        assert_eq!(name(60, &[60, 64, 67, 70, 80]), "C7b13");
+    }
+
+    #[test]
+    fn names_chords_without_a_third() {
+        assert_eq!(name(60, &[60, 67]), "C5");
+        assert_eq!(name(60, &[48, 60, 67, 72]), "C5");
+        assert_eq!(name(60, &[60, 67, 70]), "C7(no3)");
+        assert_eq!(name(60, &[60, 67, 70, 74]), "C7sus2");
+        assert_eq!(name(60, &[60, 67, 69]), "C6(no3)");
+    }
+}
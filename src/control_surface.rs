@@ -0,0 +1,104 @@
+use crate::state::GlobalState;
+use serde::{Deserialize, Serialize};
+use wmidi::{Channel, ControlFunction, MidiMessage, U7};
+
+/// How an encoder reports its position
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EncoderMode {
+    /// The CC value is the encoder position, 0-127
+    Absolute,
+    /// The CC value is a two's complement offset: 1-63 turn clockwise, 65-127 counter-clockwise
+    Relative,
+}
+
+/// Maps encoder and page button CCs to the rotary controls of the current `Page`
+#[derive(Debug, Clone)]
+pub struct ControlSurface {
+    pub channel: Channel,
+    /// CC numbers of the four encoders, in page control order
+    pub encoders: [u8; 4],
+    pub page_button: u8,
+    pub mode: EncoderMode,
+}
+
+impl ControlSurface {
+    pub fn new() -> Self {
+        // CCs 20-24 are undefined in the MIDI spec, so they won't collide with modifier mappings
+        Self {
+            channel: Channel::Ch1,
+            encoders: [20, 21, 22, 23],
+            page_button: 24,
+            mode: EncoderMode::Relative,
+        }
+    }
+
+    /// Apply a control change to the state. Returns the messages to echo back to the controller if
+    /// the message was handled.
+    pub fn handle(
+        &self,
+        state: &mut GlobalState,
+        message: &MidiMessage,
+    ) -> Option<Vec<MidiMessage<'static>>> {
+        let (function, value) = match message {
+            MidiMessage::ControlChange(channel, function, value) if *channel == self.channel => {
+                (u8::from(function.0), u8::from(*value))
+            }
+            _ => return None,
+        };
+
+        if function == self.page_button {
+            // only switch on press, not release
+            if value > 0 {
+                state.page = state.page.next();
+                println!("Switched to page {:?}", state.page);
+            }
+            return Some(self.get_feedback(state));
+        }
+
+        let index = self.encoders.iter().position(|&cc| cc == function)?;
+        let control = state.page.get_controls()[index];
+        match self.mode {
+            EncoderMode::Absolute => {
+                let (_, count) = state.get_control_position(control);
+                state.set_control_position(control, usize::from(value) * count / 128);
+            }
+            EncoderMode::Relative => {
+                let delta = if value < 64 {
+                    i32::from(value)
+                } else {
+                    i32::from(value) - 128
+                };
+                state.adjust_control(control, delta);
+            }
+        }
+        println!(
+            "Control {:?} set to {:?}",
+            control,
+            state.get_control_position(control)
+        );
+        Some(vec![self.get_encoder_feedback(state, index)])
+    }
+
+    /// Get the current value of every encoder on the current page, scaled to 0-127
+    pub fn get_feedback(&self, state: &GlobalState) -> Vec<MidiMessage<'static>> {
+        (0..self.encoders.len())
+            .map(|index| self.get_encoder_feedback(state, index))
+            .collect()
+    }
+
+    fn get_encoder_feedback(&self, state: &GlobalState, index: usize) -> MidiMessage<'static> {
+        let control = state.page.get_controls()[index];
+        let (position, count) = state.get_control_position(control);
+        let value = if count > 1 {
+            position * 127 / (count - 1)
+        } else {
+            0
+        };
+        MidiMessage::ControlChange(
+            self.channel,
+            ControlFunction(U7::from_u8_lossy(self.encoders[index])),
+            U7::from_u8_lossy(value as u8),
+        )
+    }
+}
@@ -1,17 +1,22 @@
-use crate::modifier::{self, Extension, Inversion, Quality};
+use crate::mapping::DeviceMapping;
+use crate::modifier::{MappingInput, Modifier, ModifierMapping};
+use crate::state::GlobalState;
 use device_query::{CallbackGuard, DeviceEvents, DeviceState, Keycode};
-use modifier::{Modifier, ModifierStack};
 use std::error::Error;
 use std::sync::Arc;
 use tokio::sync::RwLock;
 
+type KeyHandler = CallbackGuard<Box<dyn Fn(&Keycode) + Send + Sync>>;
+
+/// Keeps the key handlers registered until dropped
 pub struct KeyboardIn {
-    key_up_handler: CallbackGuard<Box<dyn Fn(&Keycode) + Send + Sync>>,
-    key_down_handler: CallbackGuard<Box<dyn Fn(&Keycode) + Send + Sync>>,
+    _key_up_handler: KeyHandler,
+    _key_down_handler: KeyHandler,
 }
 
 pub async fn run_input(
-    modifier_stack: Arc<RwLock<ModifierStack>>,
+    state: Arc<RwLock<GlobalState>>,
+    mapping: DeviceMapping,
 ) -> Result<KeyboardIn, Box<dyn Error>> {
     let handle_modifier = move |modifier: Modifier, is_pressed: bool| {
         println!(
@@ -19,36 +24,16 @@ pub async fn run_input(
             modifier,
             if is_pressed { "pressed" } else { "released" }
         );
-        if let Ok(mut data) = modifier_stack.try_write() {
-            data.update(modifier, is_pressed);
-        }
+        // key events arrive on the event loop's own thread, outside the runtime, so it can block
+        state
+            .blocking_write()
+            .modifier_state
+            .update(modifier, is_pressed);
     };
 
     let handle_key = move |key: Keycode, pressed: bool| {
-        // Get current keys being pressed
-
-        // Check for newly pressed keys (key down events)
-        match key {
-            // Numpad keys
-            Keycode::Numpad7 => handle_modifier(Modifier::Quality(Quality::Diminished), pressed),
-            Keycode::Numpad8 => handle_modifier(Modifier::Quality(Quality::Minor), pressed),
-            Keycode::Numpad9 => handle_modifier(Modifier::Quality(Quality::Major), pressed),
-            Keycode::NumpadSubtract => {
-                handle_modifier(Modifier::Quality(Quality::Augmented), pressed)
-            }
-            Keycode::Numpad4 => handle_modifier(Modifier::Extension(Extension::Sixth), pressed),
-            Keycode::Numpad5 => {
-                handle_modifier(Modifier::Extension(Extension::MinorSeventh), pressed)
-            }
-            Keycode::Numpad6 => {
-                handle_modifier(Modifier::Extension(Extension::MajorSeventh), pressed)
-            }
-            Keycode::NumpadAdd => handle_modifier(Modifier::Extension(Extension::Ninth), pressed),
-            Keycode::Numpad1 => handle_modifier(Modifier::Inversion(Inversion::Root), pressed),
-            Keycode::Numpad2 => handle_modifier(Modifier::Inversion(Inversion::First), pressed),
-            Keycode::Numpad3 => handle_modifier(Modifier::Inversion(Inversion::Second), pressed),
-            Keycode::NumpadEnter => handle_modifier(Modifier::Inversion(Inversion::Third), pressed),
-            _ => {}
+        for (modifier, is_pressed) in mapping.get_modifiers(MappingInput::Keycode(key, pressed)) {
+            handle_modifier(modifier, is_pressed);
         }
     };
     let handle_key_clone = handle_key.clone();
@@ -62,18 +47,17 @@ pub async fn run_input(
     // let tx_clone = tx.clone();
 
     // Initialize device state for keyboard monitoring
-    let device_state = DeviceState::new();
-    println!("\nPress numpad keys for modifiers, 'Q' to quit...");
-    let key_up_handler: CallbackGuard<Box<dyn Fn(&Keycode) + Send + Sync>> = device_state
-        .on_key_up(Box::new(move |&key| {
-            println!("Key up: {:?}", key);
-            handle_key(key, false);
-        }));
-    let key_down_handler: CallbackGuard<Box<dyn Fn(&Keycode) + Send + Sync>> = device_state
-        .on_key_down(Box::new(move |&key| {
-            println!("Key down: {:?}", key);
-            handle_key_clone(key, true);
-        }));
+    let device_state =
+        DeviceState::checked_new().ok_or("Failed to open the display for keyboard input")?;
+    println!("\nPress numpad keys for modifiers...");
+    let key_up_handler: KeyHandler = device_state.on_key_up(Box::new(move |&key| {
+        println!("Key up: {:?}", key);
+        handle_key(key, false);
+    }));
+    let key_down_handler: KeyHandler = device_state.on_key_down(Box::new(move |&key| {
+        println!("Key down: {:?}", key);
+        handle_key_clone(key, true);
+    }));
 
     // let result = tokio::spawn(async move {
     //     println!("Keyboard input task started");
@@ -86,8 +70,8 @@ pub async fn run_input(
     // });
 
     Ok(KeyboardIn {
-        key_up_handler,
-        key_down_handler,
+        _key_up_handler: key_up_handler,
+        _key_down_handler: key_down_handler,
     })
 
     // Wait for MIDI processing task to complete before exiting
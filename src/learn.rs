@@ -0,0 +1,70 @@
+use crate::control_surface::ControlSurface;
+use crate::mapping::{Binding, Mappings, Trigger, get_mapping_path};
+use crate::state::{LearnTarget, Page};
+use wmidi::MidiMessage;
+
+/// Get the trigger a message would be bound to. Releases are ignored so that learning happens on
+/// the press of a button.
+pub fn get_trigger(message: &MidiMessage) -> Option<Trigger> {
+    match message {
+        MidiMessage::ControlChange(_, function, value) if u8::from(*value) > 0 => {
+            Some(Trigger::ControlChange(u8::from(function.0)))
+        }
+        MidiMessage::NoteOn(_, note, velocity) if u8::from(*velocity) > 0 => {
+            Some(Trigger::Note(u8::from(*note)))
+        }
+        MidiMessage::ProgramChange(_, program) => Some(Trigger::Program(u8::from(*program))),
+        _ => None,
+    }
+}
+
+/// Bind a trigger from the device on `port_name` to `target`, replacing any existing binding of
+/// the trigger, and save the mappings to the user's mapping file
+pub fn bind(
+    mappings: &mut Mappings,
+    port_name: &str,
+    target: LearnTarget,
+    trigger: Trigger,
+) -> Result<(), String> {
+    // learning a control lays out a surface for devices that don't have one yet
+    let mut controls = mappings
+        .get_controls(port_name)
+        .unwrap_or_else(ControlSurface::new);
+    match (target, trigger) {
+        (LearnTarget::Modifier(_), _) => (),
+        (LearnTarget::Control(control), Trigger::ControlChange(cc)) => {
+            let (_, index) = Page::find_control(control)
+                .ok_or_else(|| format!("{:?} is not on any page", control))?;
+            // swap with the encoder that already uses this CC, if any
+            match controls.encoders.iter().position(|&e| e == cc) {
+                Some(other) => controls.encoders.swap(other, index),
+                None => controls.encoders[index] = cc,
+            }
+        }
+        (LearnTarget::PageButton, Trigger::ControlChange(cc)) => controls.page_button = cc,
+        (_, trigger) => {
+            return Err(format!(
+                "controls can only be bound to a CC, not {:?}",
+                trigger
+            ));
+        }
+    }
+
+    let mapping = mappings.for_port_mut(port_name);
+    mapping
+        .bindings
+        .retain(|binding| binding.trigger != trigger);
+    match target {
+        LearnTarget::Modifier(modifier) => mapping.bindings.push(Binding {
+            trigger,
+            channel: None,
+            modifier,
+        }),
+        LearnTarget::Control(_) | LearnTarget::PageButton => mapping.controls = Some(controls),
+    }
+
+    let path = get_mapping_path().ok_or("could not find the user's config directory")?;
+    mappings.save(&path).map_err(|e| e.to_string())?;
+    println!("Saved mappings to {}", path.display());
+    Ok(())
+}
@@ -1,20 +1,55 @@
 mod arpeggiator;
+mod chord_analysis;
+mod chord_name;
+mod control_surface;
 mod keyboard_in;
+mod learn;
+mod mapping;
 mod midi;
 mod midi_in;
 mod modifier;
 mod modifier_handler;
+mod router;
 mod state;
+mod terminal;
 mod theory;
+mod voice_leading;
+mod voicing;
 // use device_query::{DeviceQuery, DeviceState, Keycode};
+use clap::Parser;
+use mapping::Mappings;
+use midi::PortSelector;
+use midi_in::ChordStatus;
 use midir::MidiOutput;
 use midir::os::unix::VirtualOutput;
-use modifier::{Modifier, ModifierStack};
+use state::GlobalState;
 use std::error::Error;
 use std::sync::{Arc, Mutex};
+use theory::Scale;
 use tokio::sync::{RwLock, mpsc};
 use wmidi::MidiMessage;
 
+/// Plays chords from single notes, shaped by modifiers held on a controller or keyboard
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Args {
+    /// List the available MIDI ports and exit
+    #[arg(long)]
+    list_ports: bool,
+    /// Port to read notes from, by index, name or regular expression
+    #[arg(long)]
+    input: Option<PortSelector>,
+    /// Port to read modifiers and controls from; defaults to the input port
+    #[arg(long)]
+    control_input: Option<PortSelector>,
+    /// Existing port to send chords to, by index, name or regular expression
+    #[arg(long, conflicts_with = "virtual_port")]
+    output: Option<PortSelector>,
+    /// Name of the virtual port to create and send chords to
+    #[arg(long = "virtual", default_value = "Poorkid")]
+    virtual_port: String,
+}
+
 // The #[tokio::main] attribute sets up Tokio's async runtime
 // This runtime manages all concurrent tasks and handles their scheduling
 // Think of it as an event loop that efficiently juggles multiple operations
@@ -27,13 +62,29 @@ async fn main() {
 }
 
 async fn run() -> Result<(), Box<dyn Error>> {
-    // midi_in::run().await?;
+    let args = Args::parse();
+    if args.list_ports {
+        return midi::list_ports();
+    }
+
     // Initialize MIDI output with name "Poorkid"
     let midi_out = MidiOutput::new("Poorkid")?;
 
-    // Create a virtual MIDI port that other applications can connect to
-    println!("\nCreating virtual port...");
-    let midi_out_port = midi_out.create_virtual("Poorkid")?;
+    let midi_out_port = match &args.output {
+        // Connect to an existing port, e.g. a hardware synth
+        Some(selector) => {
+            let port = midi::get_midi_out_port(selector)?;
+            midi_out
+                .connect(&port, "Poorkid")
+                .map_err(|e| e.to_string())?
+        }
+        // Create a virtual MIDI port that other applications can connect to
+        None => {
+            println!("\nCreating virtual port {}...", args.virtual_port);
+            midi_out.create_virtual(&args.virtual_port)?
+        }
+    };
+    let control_input = args.control_input.or_else(|| args.input.clone());
 
     let (midi_bytes_sender, mut midi_bytes_receiver) = mpsc::channel::<Vec<u8>>(32);
 
@@ -42,12 +93,41 @@ async fn run() -> Result<(), Box<dyn Error>> {
     // - Mutex: Ensures only one thread can access the port at a time
     let midi_out_port_threadsafe = Arc::new(Mutex::new(midi_out_port));
 
-    let modifier_stack = Arc::new(RwLock::new(ModifierStack::new()));
+    let mappings = Mappings::load_or_default()?;
+
+    // Shared state read and mutated by the input tasks
+    let mut global_state = GlobalState::new();
+    global_state
+        .modifier_state
+        .set_combinations(mappings.combinations.clone());
+    global_state
+        .scales
+        .extend(mappings.scales.values().map(|&scale| Scale::Custom(scale)));
+    let state = Arc::new(RwLock::new(global_state));
+    let mappings = Arc::new(RwLock::new(mappings));
+    // Chords held on the input, shared with the terminal to release latched arpeggios
+    let status = Arc::new(RwLock::new(ChordStatus::new()));
 
-    let midi_intercept_task =
-        midi_in::run_input(midi_bytes_sender.clone(), modifier_stack.clone()).await?;
-    let modifier_handler_task = modifier_handler::handle_modifiers(modifier_stack.clone()).await?;
-    // let _keyboard_in = keyboard_in::run_input(modifier_stack).await?;
+    let router_task = router::run_router(
+        state.clone(),
+        mappings.clone(),
+        status.clone(),
+        midi_bytes_sender.clone(),
+        args.input,
+        control_input,
+    )
+    .await?;
+    let _terminal_task = terminal::run(state.clone(), mappings.clone(), status.clone());
+    let arpeggiator_task = arpeggiator::run(state.clone(), midi_bytes_sender.clone());
+    // the keyboard is optional, e.g. when running without a display
+    let keyboard = mappings.read().await.keyboard.clone();
+    let _keyboard_in = match keyboard_in::run_input(state.clone(), keyboard).await {
+        Ok(keyboard_in) => Some(keyboard_in),
+        Err(e) => {
+            println!("Keyboard input unavailable: {}", e);
+            None
+        }
+    };
 
     // Spawn a task to handle MIDI output
     let midi_output_task = tokio::spawn({
@@ -66,38 +146,16 @@ async fn run() -> Result<(), Box<dyn Error>> {
         }
     });
 
-    let bytes = vec![1, 2, 3];
-    let (send, mut receive) = mpsc::channel::<Arc<MidiMessage>>(32);
-    let message = Arc::new(MidiMessage::from_bytes(&bytes).unwrap().to_owned());
-    // create a receiver tokio thread
-    tokio::spawn(async move {
-        let port = midi_out_port_threadsafe.clone();
-        async move {
-            while let Some(message) = receive.recv().await {
-                println!("Sending to output port: {:?}", message);
-                if let Ok(mut port) = port.lock() {
-                    if let Err(e) = port.send(message.bytes()) {
-                        println!("Error sending MIDI message: {:?}", e);
-                    }
-                }
-            }
-        }
-    });
-
     // Wait for tasks to complete
     tokio::try_join!(
-        async {
-            midi_intercept_task
-                .await
-                .map_err(|e| Box::new(e) as Box<dyn Error>)
-        },
+        async { router_task.await.map_err(|e| Box::new(e) as Box<dyn Error>) },
         async {
             midi_output_task
                 .await
                 .map_err(|e| Box::new(e) as Box<dyn Error>)
         },
         async {
-            modifier_handler_task
+            arpeggiator_task
                 .await
                 .map_err(|e| Box::new(e) as Box<dyn Error>)
         }
@@ -0,0 +1,635 @@
+use crate::control_surface::{ControlSurface, EncoderMode};
+use crate::modifier::{
+    Combination, CustomQuality, Extension, Inversion, MappingInput, Modifier, ModifierMapping,
+    Quality,
+};
+use crate::theory::CustomScale;
+use device_query::Keycode;
+use serde::de::IntoDeserializer;
+use serde::de::value::{Error as ValueError, StrDeserializer};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use toml::Spanned;
+use wmidi::{Channel, MidiMessage};
+
+/// Mappings used when the user has no mapping file
+const DEFAULT_MAPPINGS: &str = include_str!("../mappings.toml");
+
+/// An input that can be bound to a modifier
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Trigger {
+    ControlChange(u8),
+    Note(u8),
+    Program(u8),
+    Key(Keycode),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Binding {
+    pub trigger: Trigger,
+    /// Only respond to MIDI messages on this channel; any channel if unset
+    pub channel: Option<Channel>,
+    pub modifier: Modifier,
+}
+
+/// The modifier bindings and control surface layout of a single device
+#[derive(Debug, Clone, Default)]
+pub struct DeviceMapping {
+    pub bindings: Vec<Binding>,
+    pub controls: Option<ControlSurface>,
+    /// Also play messages bound to modifiers as notes
+    pub forward_modifiers: bool,
+}
+
+impl DeviceMapping {
+    fn matching(&self, channel: Channel) -> impl Iterator<Item = &Binding> {
+        self.bindings
+            .iter()
+            .filter(move |binding| binding.channel.is_none_or(|c| c == channel))
+    }
+}
+
+impl ModifierMapping for DeviceMapping {
+    fn get_modifiers(&self, input: MappingInput) -> Vec<(Modifier, bool)> {
+        match input {
+            MappingInput::Keycode(key, pressed) => self
+                .bindings
+                .iter()
+                .filter(|binding| binding.trigger == Trigger::Key(key))
+                .map(|binding| (binding.modifier, pressed))
+                .collect(),
+            MappingInput::MidiMessage(msg) => match msg {
+                MidiMessage::ControlChange(channel, function, value) => self
+                    .matching(channel)
+                    .filter(|b| b.trigger == Trigger::ControlChange(u8::from(function.0)))
+                    .map(|b| (b.modifier, u8::from(value) > 0))
+                    .collect(),
+                MidiMessage::NoteOn(channel, note, velocity) => self
+                    .matching(channel)
+                    .filter(|b| b.trigger == Trigger::Note(u8::from(note)))
+                    .map(|b| (b.modifier, u8::from(velocity) > 0))
+                    .collect(),
+                MidiMessage::NoteOff(channel, note, _) => self
+                    .matching(channel)
+                    .filter(|b| b.trigger == Trigger::Note(u8::from(note)))
+                    .map(|b| (b.modifier, false))
+                    .collect(),
+                // program changes have no release, so selecting one program releases the others
+                MidiMessage::ProgramChange(channel, program) => self
+                    .matching(channel)
+                    .filter_map(|b| match b.trigger {
+                        Trigger::Program(p) => Some((b.modifier, p == u8::from(program))),
+                        _ => None,
+                    })
+                    .collect(),
+                _ => vec![],
+            },
+        }
+    }
+}
+
+/// Modifier mappings for the keyboard and for MIDI devices, keyed by input port name
+#[derive(Debug, Clone, Default)]
+pub struct Mappings {
+    pub keyboard: DeviceMapping,
+    pub devices: Vec<(Vec<String>, DeviceMapping)>,
+    /// User-defined qualities, by name
+    pub qualities: BTreeMap<String, CustomQuality>,
+    /// Modifiers played as other modifiers when held together
+    pub combinations: Vec<Combination>,
+    /// User-defined scales, by name
+    pub scales: BTreeMap<String, CustomScale>,
+}
+
+impl Mappings {
+    /// Load mappings from the user's mapping file, or the defaults if it doesn't exist
+    pub fn load_or_default() -> Result<Self, MappingError> {
+        match get_mapping_path() {
+            Some(path) if path.exists() => Self::load(&path),
+            _ => Self::parse(DEFAULT_MAPPINGS),
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self, MappingError> {
+        let source = std::fs::read_to_string(path).map_err(|e| MappingError {
+            line: None,
+            message: format!("could not read {}: {}", path.display(), e),
+        })?;
+        Self::parse(&source).map_err(|e| MappingError {
+            message: format!("{}: {}", path.display(), e.message),
+            ..e
+        })
+    }
+
+    pub fn parse(source: &str) -> Result<Self, MappingError> {
+        let file: MappingFile = toml::from_str(source).map_err(|e| MappingError {
+            line: e.span().map(|span| get_line(source, span.start)),
+            message: e.message().to_string(),
+        })?;
+
+        let qualities = file
+            .qualities
+            .into_iter()
+            .map(|(name, tones)| {
+                let line = Some(get_line(source, tones.span().start));
+                if find_quality(&name, &BTreeMap::new()).is_some() {
+                    return Err(MappingError {
+                        line,
+                        message: format!("quality {} is already built in", name),
+                    });
+                }
+                let quality = CustomQuality::new(tones.get_ref()).ok_or_else(|| MappingError {
+                    line,
+                    message: format!(
+                        "quality {} must have at most {} tones, each 1-36 semitones above the root",
+                        name,
+                        CustomQuality::MAX_TONES
+                    ),
+                })?;
+                Ok((name, quality))
+            })
+            .collect::<Result<_, _>>()?;
+
+        let keyboard = match file.keyboard {
+            Some(device) => parse_device(source, device.modifiers, true, &qualities)?,
+            None => DeviceMapping::default(),
+        };
+        let devices = file
+            .devices
+            .into_iter()
+            .map(|device| {
+                if device.ports.get_ref().is_empty() {
+                    return Err(MappingError {
+                        line: Some(get_line(source, device.ports.span().start)),
+                        message: "device must list at least one port".to_string(),
+                    });
+                }
+                let ports = device.ports.get_ref().clone();
+                let mut mapping = parse_device(source, device.modifiers, false, &qualities)?;
+                mapping.controls = device
+                    .controls
+                    .map(|entry| {
+                        let line = get_line(source, entry.span().start);
+                        parse_controls(entry.into_inner()).map_err(|message| MappingError {
+                            line: Some(line),
+                            message,
+                        })
+                    })
+                    .transpose()?;
+                mapping.forward_modifiers = device.forward_modifiers;
+                Ok((ports, mapping))
+            })
+            .collect::<Result<_, _>>()?;
+
+        let scales = file
+            .scales
+            .into_iter()
+            .map(|(name, steps)| {
+                let scale = CustomScale::new(steps.get_ref()).ok_or_else(|| MappingError {
+                    line: Some(get_line(source, steps.span().start)),
+                    message: format!(
+                        "scale {} must have steps of at least one semitone adding up to 12",
+                        name
+                    ),
+                })?;
+                Ok((name, scale))
+            })
+            .collect::<Result<_, _>>()?;
+
+        let combinations = file
+            .combinations
+            .into_iter()
+            .map(|entry| {
+                let line = get_line(source, entry.span().start);
+                parse_combination(entry.into_inner(), &qualities).map_err(|message| MappingError {
+                    line: Some(line),
+                    message,
+                })
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            keyboard,
+            devices,
+            qualities,
+            combinations,
+            scales,
+        })
+    }
+
+    /// Get a built-in or user-defined quality by the name it has in the mapping file
+    pub fn get_quality(&self, name: &str) -> Option<Quality> {
+        find_quality(name, &self.qualities)
+    }
+
+    /// Get the mapping for the device connected to the given input port
+    pub fn for_port(&self, port_name: &str) -> Option<&DeviceMapping> {
+        self.devices
+            .iter()
+            .find(|(ports, _)| ports.iter().any(|p| p == port_name))
+            .map(|(_, mapping)| mapping)
+    }
+
+    /// Get the mapping for the given input port, adding an empty one if there is none
+    pub fn for_port_mut(&mut self, port_name: &str) -> &mut DeviceMapping {
+        let index = match self
+            .devices
+            .iter()
+            .position(|(ports, _)| ports.iter().any(|p| p == port_name))
+        {
+            Some(index) => index,
+            None => {
+                self.devices
+                    .push((vec![port_name.to_string()], DeviceMapping::default()));
+                self.devices.len() - 1
+            }
+        };
+        &mut self.devices[index].1
+    }
+
+    /// Get the control surface layout for the given input port, or None if it has no controls
+    pub fn get_controls(&self, port_name: &str) -> Option<ControlSurface> {
+        self.for_port(port_name)
+            .and_then(|mapping| mapping.controls.clone())
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), MappingError> {
+        let to_error = |e: &dyn fmt::Display| MappingError {
+            line: None,
+            message: format!("could not write {}: {}", path.display(), e),
+        };
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).map_err(|e| to_error(&e))?;
+        }
+        let file = MappingFile {
+            keyboard: Some(DeviceEntry {
+                modifiers: self
+                    .keyboard
+                    .bindings
+                    .iter()
+                    .map(|binding| BindingEntry::spanned(binding, &self.qualities))
+                    .collect(),
+            }),
+            devices: self
+                .devices
+                .iter()
+                .map(|(ports, mapping)| PortsEntry {
+                    ports: Spanned::new(0..0, ports.clone()),
+                    modifiers: mapping
+                        .bindings
+                        .iter()
+                        .map(|binding| BindingEntry::spanned(binding, &self.qualities))
+                        .collect(),
+                    controls: mapping.controls.as_ref().map(|controls| {
+                        Spanned::new(
+                            0..0,
+                            ControlsEntry {
+                                channel: controls.channel.number(),
+                                encoders: controls.encoders,
+                                page_button: controls.page_button,
+                                mode: controls.mode,
+                            },
+                        )
+                    }),
+                    forward_modifiers: mapping.forward_modifiers,
+                })
+                .collect(),
+            qualities: self
+                .qualities
+                .iter()
+                .map(|(name, quality)| {
+                    (
+                        name.clone(),
+                        Spanned::new(0..0, quality.get_tones().to_vec()),
+                    )
+                })
+                .collect(),
+            scales: self
+                .scales
+                .iter()
+                .map(|(name, scale)| (name.clone(), Spanned::new(0..0, scale.get_intervals())))
+                .collect(),
+            combinations: self
+                .combinations
+                .iter()
+                .map(|combination| {
+                    let names = |modifiers: &[Modifier]| {
+                        modifiers
+                            .iter()
+                            .filter_map(|&m| get_modifier_name(m, &self.qualities))
+                            .collect()
+                    };
+                    Spanned::new(
+                        0..0,
+                        CombinationEntry {
+                            held: names(&combination.held),
+                            result: names(&combination.result),
+                        },
+                    )
+                })
+                .collect(),
+        };
+        let source = toml::to_string(&file).map_err(|e| to_error(&e))?;
+        std::fs::write(path, source).map_err(|e| to_error(&e))
+    }
+}
+
+/// Get the path of the user's mapping file, `~/.config/poorkid/mappings.toml` by default
+pub fn get_mapping_path() -> Option<PathBuf> {
+    let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(config_dir.join("poorkid").join("mappings.toml"))
+}
+
+#[derive(Debug)]
+pub struct MappingError {
+    /// 1-based line of the offending entry, if known
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for MappingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "line {}: {}", line, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl Error for MappingError {}
+
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct MappingFile {
+    #[serde(default)]
+    qualities: BTreeMap<String, Spanned<Vec<i8>>>,
+    #[serde(default)]
+    scales: BTreeMap<String, Spanned<Vec<u8>>>,
+    keyboard: Option<DeviceEntry>,
+    #[serde(default)]
+    devices: Vec<PortsEntry>,
+    #[serde(default)]
+    combinations: Vec<Spanned<CombinationEntry>>,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct PortsEntry {
+    ports: Spanned<Vec<String>>,
+    #[serde(default)]
+    modifiers: Vec<Spanned<BindingEntry>>,
+    controls: Option<Spanned<ControlsEntry>>,
+    #[serde(default)]
+    forward_modifiers: bool,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct DeviceEntry {
+    #[serde(default)]
+    modifiers: Vec<Spanned<BindingEntry>>,
+}
+
+#[derive(Deserialize, Serialize, Default)]
+#[serde(deny_unknown_fields)]
+struct BindingEntry {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cc: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    note: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    program: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    channel: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    quality: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    extension: Option<Extension>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    inversion: Option<Inversion>,
+}
+
+impl BindingEntry {
+    fn spanned(binding: &Binding, qualities: &BTreeMap<String, CustomQuality>) -> Spanned<Self> {
+        let mut entry = Self {
+            channel: binding.channel.map(Channel::number),
+            ..Default::default()
+        };
+        match binding.trigger {
+            Trigger::ControlChange(cc) => entry.cc = Some(cc),
+            Trigger::Note(note) => entry.note = Some(note),
+            Trigger::Program(program) => entry.program = Some(program),
+            Trigger::Key(key) => entry.key = Some(key.to_string()),
+        }
+        match binding.modifier {
+            Modifier::Quality(quality) => entry.quality = get_quality_name(quality, qualities),
+            Modifier::Extension(extension) => entry.extension = Some(extension),
+            Modifier::Inversion(inversion) => entry.inversion = Some(inversion),
+        }
+        Spanned::new(0..0, entry)
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct CombinationEntry {
+    held: Vec<String>,
+    result: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct ControlsEntry {
+    channel: u8,
+    encoders: [u8; 4],
+    page_button: u8,
+    mode: EncoderMode,
+}
+
+fn parse_device(
+    source: &str,
+    modifiers: Vec<Spanned<BindingEntry>>,
+    keyboard: bool,
+    qualities: &BTreeMap<String, CustomQuality>,
+) -> Result<DeviceMapping, MappingError> {
+    let bindings = modifiers
+        .into_iter()
+        .map(|entry| {
+            let line = get_line(source, entry.span().start);
+            parse_binding(entry.into_inner(), keyboard, qualities).map_err(|message| MappingError {
+                line: Some(line),
+                message,
+            })
+        })
+        .collect::<Result<_, _>>()?;
+    Ok(DeviceMapping {
+        bindings,
+        controls: None,
+        forward_modifiers: false,
+    })
+}
+
+fn parse_controls(entry: ControlsEntry) -> Result<ControlSurface, String> {
+    if let Some(cc) = entry
+        .encoders
+        .iter()
+        .chain([&entry.page_button])
+        .find(|&&cc| cc > 127)
+    {
+        return Err(format!("cc {} is out of range 0-127", cc));
+    }
+    Ok(ControlSurface {
+        channel: parse_channel(entry.channel)?,
+        encoders: entry.encoders,
+        page_button: entry.page_button,
+        mode: entry.mode,
+    })
+}
+
+fn parse_channel(channel: u8) -> Result<Channel, String> {
+    match channel {
+        1..=16 => Ok(Channel::from_index(channel - 1).unwrap()),
+        _ => Err(format!("channel {} is out of range 1-16", channel)),
+    }
+}
+
+fn parse_binding(
+    entry: BindingEntry,
+    keyboard: bool,
+    qualities: &BTreeMap<String, CustomQuality>,
+) -> Result<Binding, String> {
+    let midi_value = |name: &str, value: u8| {
+        if value > 127 {
+            Err(format!("{} {} is out of range 0-127", name, value))
+        } else {
+            Ok(value)
+        }
+    };
+
+    let trigger = match (entry.cc, entry.note, entry.program, entry.key) {
+        (Some(cc), None, None, None) => Trigger::ControlChange(midi_value("cc", cc)?),
+        (None, Some(note), None, None) => Trigger::Note(midi_value("note", note)?),
+        (None, None, Some(program), None) => Trigger::Program(midi_value("program", program)?),
+        (None, None, None, Some(key)) => Trigger::Key(Keycode::from_str(&key)?),
+        (None, None, None, None) => return Err("missing cc, note, program or key".to_string()),
+        _ => return Err("only one of cc, note, program or key may be set".to_string()),
+    };
+    match (trigger, keyboard) {
+        (Trigger::Key(_), false) => return Err("key mappings belong in [keyboard]".to_string()),
+        (Trigger::Key(_), true) => (),
+        (_, true) => return Err("the keyboard only supports key mappings".to_string()),
+        (_, false) => (),
+    }
+
+    let channel = match entry.channel {
+        Some(_) if keyboard => return Err("key mappings have no channel".to_string()),
+        Some(channel) => Some(parse_channel(channel)?),
+        None => None,
+    };
+
+    let modifier = match (entry.quality, entry.extension, entry.inversion) {
+        (Some(quality), None, None) => Modifier::Quality(
+            find_quality(&quality, qualities)
+                .ok_or_else(|| format!("unknown quality {}", quality))?,
+        ),
+        (None, Some(extension), None) => Modifier::Extension(extension),
+        (None, None, Some(inversion)) => Modifier::Inversion(inversion),
+        (None, None, None) => return Err("missing quality, extension or inversion".to_string()),
+        _ => return Err("only one of quality, extension or inversion may be set".to_string()),
+    };
+
+    Ok(Binding {
+        trigger,
+        channel,
+        modifier,
+    })
+}
+
+fn parse_combination(
+    entry: CombinationEntry,
+    qualities: &BTreeMap<String, CustomQuality>,
+) -> Result<Combination, String> {
+    if entry.held.is_empty() {
+        return Err("combination must hold at least one modifier".to_string());
+    }
+    let modifiers = |names: Vec<String>| {
+        names
+            .into_iter()
+            .map(|name| {
+                find_modifier(&name, qualities).ok_or_else(|| format!("unknown modifier {}", name))
+            })
+            .collect::<Result<Vec<_>, _>>()
+    };
+    Ok(Combination {
+        held: modifiers(entry.held)?,
+        result: modifiers(entry.result)?,
+    })
+}
+
+/// Get a quality, extension or inversion by the name it has in the mapping file. The names of
+/// the three kinds don't overlap.
+fn find_modifier(name: &str, qualities: &BTreeMap<String, CustomQuality>) -> Option<Modifier> {
+    let deserializer = || -> StrDeserializer<ValueError> { name.into_deserializer() };
+    find_quality(name, qualities)
+        .map(Modifier::Quality)
+        .or_else(|| {
+            Extension::deserialize(deserializer())
+                .ok()
+                .map(Modifier::Extension)
+        })
+        .or_else(|| {
+            Inversion::deserialize(deserializer())
+                .ok()
+                .map(Modifier::Inversion)
+        })
+}
+
+/// Get the name of a modifier in the mapping file
+fn get_modifier_name(
+    modifier: Modifier,
+    qualities: &BTreeMap<String, CustomQuality>,
+) -> Option<String> {
+    let name = |value: Result<toml::Value, _>| value.ok()?.as_str().map(str::to_string);
+    match modifier {
+        Modifier::Quality(quality) => get_quality_name(quality, qualities),
+        Modifier::Extension(extension) => name(toml::Value::try_from(extension)),
+        Modifier::Inversion(inversion) => name(toml::Value::try_from(inversion)),
+    }
+}
+
+/// Get the name of a built-in or user-defined quality in the mapping file
+fn get_quality_name(
+    quality: Quality,
+    qualities: &BTreeMap<String, CustomQuality>,
+) -> Option<String> {
+    match quality {
+        Quality::Custom(custom) => qualities
+            .iter()
+            .find(|(_, quality)| **quality == custom)
+            .map(|(name, _)| name.clone()),
+        quality => toml::Value::try_from(quality)
+            .ok()
+            .and_then(|value| value.as_str().map(str::to_string)),
+    }
+}
+
+/// Get a built-in quality by its snake_case name, or else a user-defined one
+fn find_quality(name: &str, qualities: &BTreeMap<String, CustomQuality>) -> Option<Quality> {
+    let deserializer: StrDeserializer<ValueError> = name.into_deserializer();
+    Quality::deserialize(deserializer)
+        .ok()
+        .or_else(|| qualities.get(name).copied().map(Quality::Custom))
+}
+
+/// Get the 1-based line number of a byte offset in `source`
+fn get_line(source: &str, offset: usize) -> usize {
+    source[..offset.min(source.len())].matches('\n').count() + 1
+}
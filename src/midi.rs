@@ -1,25 +1,109 @@
 use midir::{MidiInput, MidiInputPort, MidiOutput, MidiOutputPort};
+use regex::Regex;
 use std::error::Error;
+use std::str::FromStr;
 
-pub fn get_midi_in_port() -> Result<MidiInputPort, Box<dyn Error>> {
-    // Get the first available input port
+/// Selects a MIDI port by index, exact name, or regular expression matched against port names
+#[derive(Debug, Clone)]
+pub enum PortSelector {
+    Index(usize),
+    Name(String),
+}
+
+impl FromStr for PortSelector {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.parse::<usize>() {
+            Ok(index) => Ok(PortSelector::Index(index)),
+            Err(_) => {
+                // validate the pattern up front so typos are reported before connecting
+                Regex::new(s).map_err(|e| e.to_string())?;
+                Ok(PortSelector::Name(s.to_string()))
+            }
+        }
+    }
+}
+
+impl PortSelector {
+    /// Get the index of the selected port among `names`. Exact names take precedence over
+    /// regular expression matches.
+    fn select(&self, names: &[String]) -> Option<usize> {
+        match self {
+            PortSelector::Index(index) => (*index < names.len()).then_some(*index),
+            PortSelector::Name(name) => names.iter().position(|n| n == name).or_else(|| {
+                let pattern = Regex::new(name).ok()?;
+                names.iter().position(|n| pattern.is_match(n))
+            }),
+        }
+    }
+}
+
+/// Get the selected input port, or the OP-XY or first available port if none is selected
+pub fn get_midi_in_port(selector: Option<&PortSelector>) -> Result<MidiInputPort, Box<dyn Error>> {
     let midi_in = MidiInput::new("Poorkid Input")?;
     let in_ports = midi_in.ports();
-    let input_port = {
-        let mut selected_port = None;
-        for port in in_ports.iter() {
-            if let Ok(name) = midi_in.port_name(port) {
-                if name == "OP-XY" || name == "OP-XY Bluetooth" {
-                    println!("Found OP-XY");
-                    selected_port = Some(port);
-                    break;
-                }
+    let names: Vec<String> = in_ports
+        .iter()
+        .map(|port| midi_in.port_name(port).unwrap_or_default())
+        .collect();
+    let index = match selector {
+        Some(selector) => selector
+            .select(&names)
+            .ok_or_else(|| format!("no input port matches {:?}", selector))?,
+        None => match names
+            .iter()
+            .position(|name| name == "OP-XY" || name == "OP-XY Bluetooth")
+        {
+            Some(index) => {
+                println!("Found OP-XY");
+                index
             }
-        }
-        selected_port
-            .or_else(|| in_ports.get(0))
-            .ok_or_else(|| Box::<dyn Error>::from("no input port found"))?
-            .clone()
+            None if !in_ports.is_empty() => 0,
+            None => return Err("no input port found".into()),
+        },
     };
-    Ok(input_port)
+    println!("Using input port {}", names[index]);
+    Ok(in_ports[index].clone())
+}
+
+/// Get the selected output port
+pub fn get_midi_out_port(selector: &PortSelector) -> Result<MidiOutputPort, Box<dyn Error>> {
+    let midi_out = MidiOutput::new("Poorkid Output")?;
+    let out_ports = midi_out.ports();
+    let names: Vec<String> = out_ports
+        .iter()
+        .map(|port| midi_out.port_name(port).unwrap_or_default())
+        .collect();
+    let index = selector
+        .select(&names)
+        .ok_or_else(|| format!("no output port matches {:?}", selector))?;
+    println!("Using output port {}", names[index]);
+    Ok(out_ports[index].clone())
+}
+
+/// Get the output port with exactly the given name, e.g. to send feedback to a controller's
+/// input port
+pub fn find_midi_out_port(name: &str) -> Result<MidiOutputPort, Box<dyn Error>> {
+    let midi_out = MidiOutput::new("Poorkid Output")?;
+    midi_out
+        .ports()
+        .into_iter()
+        .find(|port| midi_out.port_name(port).is_ok_and(|n| n == name))
+        .ok_or_else(|| format!("no output port named {}", name).into())
+}
+
+/// Print the index and name of every input and output port
+pub fn list_ports() -> Result<(), Box<dyn Error>> {
+    let midi_in = MidiInput::new("Poorkid Input")?;
+    println!("Input ports:");
+    for (index, port) in midi_in.ports().iter().enumerate() {
+        println!("  {}: {}", index, midi_in.port_name(port)?);
+    }
+    let midi_out = MidiOutput::new("Poorkid Output")?;
+    println!("Output ports:");
+    for (index, port) in midi_out.ports().iter().enumerate() {
+        println!("  {}: {}", index, midi_out.port_name(port)?);
+    }
+    Ok(())
 }
@@ -1,8 +1,6 @@
-use crate::midi::get_midi_in_port;
-use crate::modifier::ModifierStack;
-use midir::{MidiInput, MidiOutput};
+use crate::chord_name::get_chord_name;
+use crate::state::{GlobalState, Perform};
 use std::collections::HashMap;
-use std::error::Error;
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::sync::{RwLock, mpsc};
@@ -11,196 +9,218 @@ use wmidi::{Channel, MidiMessage, Note, U7};
 
 pub struct ChordStatus {
     pub roots: HashMap<Channel, HashMap<Note, Vec<Note>>>,
+    /// The last chord played, for voice leading
+    pub previous: Option<Vec<Note>>,
+    /// Bass notes sounding for each held root, so they're released on the channel they were
+    /// played on
+    pub basses: HashMap<(Channel, Note), (Channel, Note)>,
+    /// The perform mode each held root was played in, so it's released the same way
+    pub performs: HashMap<(Channel, Note), Perform>,
+    /// Strums still sending their notes, so releasing the root stops them
+    pub strums: HashMap<(Channel, Note), JoinHandle<()>>,
 }
 
 impl ChordStatus {
     pub fn new() -> Self {
         Self {
             roots: HashMap::new(),
+            previous: None,
+            basses: HashMap::new(),
+            performs: HashMap::new(),
+            strums: HashMap::new(),
         }
     }
 
-    pub fn insert(&mut self, channel: Channel, note: Note, chord: &Vec<Note>) {
+    pub fn insert(&mut self, channel: Channel, note: Note, chord: &[Note], perform: Perform) {
         self.roots
             .entry(channel)
-            .or_insert(HashMap::new())
-            .insert(note, chord.clone());
+            .or_default()
+            .insert(note, chord.to_vec());
+        self.performs.insert((channel, note), perform);
+    }
+
+    /// Remove a held root, returning its chord and the perform mode it was played in
+    pub fn remove(&mut self, channel: Channel, note: Note) -> Option<(Vec<Note>, Perform)> {
+        let chord = self.roots.get_mut(&channel)?.remove(&note)?;
+        let perform = self
+            .performs
+            .remove(&(channel, note))
+            .unwrap_or(Perform::None);
+        Some((chord, perform))
+    }
+
+    /// Whether no roots are currently held on any channel
+    pub fn is_empty(&self) -> bool {
+        self.roots.values().all(HashMap::is_empty)
     }
 }
 
-async fn transform_message(
-    stack: Arc<RwLock<ModifierStack>>,
+pub async fn transform_message(
+    state: Arc<RwLock<GlobalState>>,
     status: Arc<RwLock<ChordStatus>>,
     tx: mpsc::Sender<Vec<u8>>,
-    message: Vec<u8>,
+    midi_message: &MidiMessage<'_>,
 ) {
-    println!("Transforming message: {:?}", message);
-    let midi_message = match MidiMessage::from_bytes(&message) {
-        Ok(msg) => msg,
-        Err(e) => {
-            println!("Error parsing MIDI message: {:?}", e);
-            return;
-        }
-    };
-    let mut off = false;
-    let messages = match midi_message {
+    println!("Transforming message: {:?}", midi_message);
+    let messages = match *midi_message {
         MidiMessage::NoteOn(channel, note, velocity) => {
             println!("NoteOn: {:?}", midi_message);
-            let x = stack.read().await;
-            let notes = x.get_notes(note);
-
             let mut status = status.write().await;
-            status.insert(channel, note, &notes);
+            let mut state = state.write().await;
+            // the chord is built on the quantized note, but tracked by the played one
+            let root = match state.quantize {
+                Some(quantize) => match state.key.quantize(note, quantize) {
+                    Some(root) => root,
+                    None => {
+                        println!("Dropping note {:?} outside the key", note);
+                        return;
+                    }
+                },
+                None => note,
+            };
+            let mut notes = state.get_chord(root);
+            let bass = state.bass.and_then(|bass| {
+                let bass_note = state.get_bass(root, &bass)?;
+                // the chord root is left to the bass, even when an inversion puts another tone
+                // in the bass
+                let pitch_class = u8::from(state.get_chord_root(root)) % 12;
+                // never leave the chord empty
+                if bass.omit_from_chord && notes.iter().any(|n| u8::from(*n) % 12 != pitch_class) {
+                    notes.retain(|n| u8::from(*n) % 12 != pitch_class);
+                }
+                Some((bass.channel, bass_note))
+            });
+            if let Some((bass_channel, bass_note)) = bass {
+                status
+                    .basses
+                    .insert((channel, note), (bass_channel, bass_note));
+                send_midi_message(
+                    &tx,
+                    MidiMessage::NoteOn(bass_channel, bass_note, velocity).to_vec(),
+                )
+                .await;
+            }
+            if let Some(voice_leading) = state.voice_leading {
+                notes = voice_leading.lead(status.previous.as_deref(), &notes);
+                status.previous = Some(notes.clone());
+            }
+            println!(
+                "Chord: {}",
+                get_chord_name(state.get_chord_root(root), &notes)
+            );
+            if state.perform == Perform::Strum2Octave {
+                let octave_up: Vec<Note> = notes.iter().filter_map(|n| n.step(12).ok()).collect();
+                notes.extend(octave_up);
+                notes.sort();
+                notes.dedup();
+            }
+            let arpeggio = state.perform.is_arpeggio();
+            if arpeggio {
+                state.perform_params.arpeggiator.channel = channel;
+                state.perform_params.arpeggiator.velocity = velocity;
+            }
+            // a new chord replaces any latched one
+            if status.is_empty() {
+                state.active_notes.clear();
+            }
+            state.add_active_notes(&notes);
+            status.insert(channel, note, &notes, state.perform);
 
-            let mut messages = vec![midi_message];
-            messages.extend(
-                notes
+            match state.perform {
+                // the arpeggiator plays the held notes instead
+                Perform::Arpeggio | Perform::Arpeggio2Octave => vec![],
+                Perform::None => notes
                     .iter()
-                    .map(|note| MidiMessage::NoteOn(channel, *note, velocity)),
-            );
-            messages
+                    .map(|note| MidiMessage::NoteOn(channel, *note, velocity))
+                    .collect(),
+                Perform::Strum | Perform::Strum2Octave => {
+                    let params = &mut state.perform_params;
+                    let spacing = Duration::from_millis(u64::from(params.spacing));
+                    if params.next_strum_down() {
+                        notes.reverse();
+                    }
+                    // each string after the first is played a little softer
+                    let falloff = params.velocity_falloff;
+                    let messages: Vec<Vec<u8>> = notes
+                        .iter()
+                        .enumerate()
+                        .map(|(i, note)| {
+                            let reduction = falloff.saturating_mul(i.min(u8::MAX as usize) as u8);
+                            let velocity = u8::from(velocity).saturating_sub(reduction).max(1);
+                            MidiMessage::NoteOn(channel, *note, U7::from_u8_lossy(velocity))
+                                .to_vec()
+                        })
+                        .collect();
+                    // strum in the background so other input isn't held up while it plays
+                    let strum = tokio::spawn(strum(tx.clone(), messages, spacing));
+                    status.strums.insert((channel, note), strum);
+                    vec![]
+                }
+            }
         }
         MidiMessage::NoteOff(channel, note, velocity) => {
             println!("NoteOff: {:?}", midi_message);
-            off = true;
+            // notes the strum hasn't reached yet are never played
+            if let Some(strum) = status.write().await.strums.remove(&(channel, note)) {
+                strum.abort();
+            }
+            let bass = status.write().await.basses.remove(&(channel, note));
+            if let Some((bass_channel, bass_note)) = bass {
+                send_midi_message(
+                    &tx,
+                    MidiMessage::NoteOff(bass_channel, bass_note, velocity).to_vec(),
+                )
+                .await;
+            }
             // get existing notes and remove from status
-            let notes = {
-                let mut status = status.write().await;
-                match status.roots.get_mut(&channel) {
-                    Some(channel_notes) => match channel_notes.get(&note) {
-                        Some(_) => channel_notes.remove(&note).unwrap(),
-                        None => {
-                            println!(
-                                "No notes found for note {:?} on channel {:?}",
-                                note, channel
-                            );
-                            return;
-                        }
-                    },
-                    None => {
-                        println!("No notes found for channel {:?}", channel);
-                        return;
-                    }
+            let (notes, perform) = match status.write().await.remove(channel, note) {
+                Some(chord) => chord,
+                None => {
+                    println!(
+                        "No notes found for note {:?} on channel {:?}",
+                        note, channel
+                    );
+                    return;
                 }
             };
-
-            let mut messages = vec![midi_message];
-            messages.extend(
+            let released_all = status.read().await.is_empty();
+            let mut state = state.write().await;
+            if perform.is_arpeggio() {
+                // keep the last chord playing until the next one starts
+                if !(state.perform_params.arpeggiator.latch && released_all) {
+                    state.remove_active_notes(&notes);
+                }
+                vec![]
+            } else {
+                state.remove_active_notes(&notes);
                 notes
                     .iter()
-                    .map(|note| MidiMessage::NoteOff(channel, *note, velocity)),
-            );
-            messages
+                    .map(|note| MidiMessage::NoteOff(channel, *note, velocity))
+                    .collect()
+            }
         }
         _ => {
             vec![]
         }
     };
 
-    futures::future::join_all(messages.iter().enumerate().map(|(i, midi_message)| {
-        schedule_midi_message(
-            tx.clone(),
-            Duration::from_millis(if off { 0 } else { i as u64 * 10 }),
-            midi_message.to_vec(),
-        )
-    }))
-    .await;
-}
-
-async fn schedule_midi_message(tx: mpsc::Sender<Vec<u8>>, delay: Duration, midi_message: Vec<u8>) {
-    tokio::time::sleep(delay).await;
-    if let Err(e) = tx.send(midi_message).await {
-        println!("Failed to send MIDI message: {:?}", e);
+    for midi_message in messages {
+        send_midi_message(&tx, midi_message.to_vec()).await;
     }
 }
 
-pub async fn run_input(
-    tx: mpsc::Sender<Vec<u8>>,
-    modifier_stack: Arc<RwLock<ModifierStack>>,
-) -> Result<JoinHandle<()>, Box<dyn Error>> {
-    // create new virtual output port
-    let midi_out = MidiOutput::new("Poorkid")?;
-
-    // Create a virtual MIDI port that other applications can connect to
-    // println!("\nCreating virtual port...");
-    // let mut out_port = midi_out.create_virtual("Poorkid")?;
-
-    // Create channel for communication between MIDI callback and async task
-    let (callback_tx, mut callback_rx) = mpsc::channel::<Vec<u8>>(1024); // Increased buffer size
-
-    // Create a channel for error reporting
-    let (error_tx, mut error_rx) = mpsc::channel::<String>(32);
-    let error_tx_clone = error_tx.clone();
-
-    let midi_in = MidiInput::new("Poorkid Input")?;
-    let input_port = get_midi_in_port()?;
-
-    // Create connection and move ownership of tx to the callback
-    let _conn = midi_in.connect(
-        &input_port,
-        "midi-input",
-        move |_stamp, message, _| {
-            println!("Callback received message. Sending to receiver.");
-            if let Err(e) = callback_tx.try_send(message.to_vec()) {
-                println!("Failed to send message from callback: {:?}", e);
-                let _ = error_tx.try_send(format!("Callback send error: {:?}", e));
-            }
-        },
-        (),
-    )?;
-
-    let input_task = tokio::spawn(async move {
-        println!("MIDI input task started");
-        // move ownership of conn to the async task, otherwise it will be closed when returning
-        let conn = _conn;
-        let status = Arc::new(RwLock::new(ChordStatus::new()));
-
-        // Spawn a monitoring task
-        let monitor_task = tokio::spawn(async move {
-            while let Some(error) = error_rx.recv().await {
-                println!("Error received: {}", error);
-            }
-        });
-
-        let result: Result<(), Box<dyn Error + Send + Sync>> = async {
-            while let Some(message) = callback_rx.recv().await {
-                println!("Received message, sending to main receiver");
-                match MidiMessage::from_bytes(&message) {
-                    Ok(midi_message) => {
-                        println!("Sending MIDI message outer: {:?}", midi_message);
-                        transform_message(
-                            modifier_stack.clone(),
-                            status.clone(),
-                            tx.clone(),
-                            midi_message.to_vec(),
-                        )
-                        .await;
-                    }
-                    Err(e) => {
-                        let err_msg = format!(
-                            "Failed to parse MIDI message: {:?} - Error: {:?}",
-                            message, e
-                        );
-                        println!("{}", err_msg);
-                        error_tx_clone.send(err_msg).await?;
-                    }
-                }
-            }
-            Ok(())
-        }
-        .await;
-
-        if let Err(e) = result {
-            println!("MIDI input task error: {:?}", e);
-            error_tx_clone
-                .send(format!("MIDI input task error: {:?}", e))
-                .await
-                .unwrap_or_default();
+/// Send the notes of a strum one after another, `spacing` apart
+async fn strum(tx: mpsc::Sender<Vec<u8>>, messages: Vec<Vec<u8>>, spacing: Duration) {
+    for (i, midi_message) in messages.into_iter().enumerate() {
+        if i > 0 {
+            tokio::time::sleep(spacing).await;
         }
+        send_midi_message(&tx, midi_message).await;
+    }
+}
 
-        println!("MIDI input task ended");
-        conn.close();
-    });
-
-    Ok(input_task)
+async fn send_midi_message(tx: &mpsc::Sender<Vec<u8>>, midi_message: Vec<u8>) {
+    if let Err(e) = tx.send(midi_message).await {
+        println!("Failed to send MIDI message: {:?}", e);
+    }
 }
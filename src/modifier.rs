@@ -1,14 +1,12 @@
-use std::fmt;
-
 use device_query::Keycode;
-use wmidi::{ControlFunction, MidiMessage, Note};
+use serde::{Deserialize, Serialize};
+use wmidi::{MidiMessage, Note};
 
-#[derive(Debug, Clone, Copy, PartialEq)]
-struct Triad {
-    third: i8,
-    fifth: i8,
-}
-#[derive(Debug, Clone, Copy, PartialEq)]
+use crate::theory::{Key, NonScaleRule, Stacking};
+use crate::voicing::Voicing;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Quality {
     Diminished,
     Minor,
@@ -16,22 +14,57 @@ pub enum Quality {
     Augmented,
     Sus2,
     Sus4,
+    /// A quality defined in the mapping file, which refers to it by name
+    #[serde(skip)]
+    Custom(CustomQuality),
 }
 
 impl Quality {
-    fn get_triad(&self) -> Triad {
+    /// Semitones above the root of the tones the quality adds
+    fn get_tones(&self) -> &[i8] {
         match self {
-            Quality::Diminished => Triad { third: 3, fifth: 6 },
-            Quality::Minor => Triad { third: 3, fifth: 7 },
-            Quality::Major => Triad { third: 4, fifth: 7 },
-            Quality::Augmented => Triad { third: 4, fifth: 8 },
-            Quality::Sus2 => Triad { third: 2, fifth: 7 },
-            Quality::Sus4 => Triad { third: 5, fifth: 7 },
+            Quality::Diminished => &[3, 6],
+            Quality::Minor => &[3, 7],
+            Quality::Major => &[4, 7],
+            Quality::Augmented => &[4, 8],
+            Quality::Sus2 => &[2, 7],
+            Quality::Sus4 => &[5, 7],
+            Quality::Custom(custom) => custom.get_tones(),
         }
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+/// A user-defined chord quality, such as a power chord or a quartal stack
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CustomQuality {
+    tones: [i8; CustomQuality::MAX_TONES],
+    len: usize,
+}
+
+impl CustomQuality {
+    pub const MAX_TONES: usize = 8;
+
+    /// Create a quality from semitones above the root, or None if there are too many tones or
+    /// any is outside the three octaves above the root
+    pub fn new(tones: &[i8]) -> Option<Self> {
+        if tones.len() > Self::MAX_TONES || tones.iter().any(|t| !(1..=36).contains(t)) {
+            return None;
+        }
+        let mut quality = Self {
+            tones: [0; Self::MAX_TONES],
+            len: tones.len(),
+        };
+        quality.tones[..tones.len()].copy_from_slice(tones);
+        Some(quality)
+    }
+
+    pub fn get_tones(&self) -> &[i8] {
+        &self.tones[..self.len]
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Extension {
     FlatSixth,
     Sixth,
@@ -46,30 +79,87 @@ pub enum Extension {
     FlatThirteenth,
     Thirteenth,
     SharpThirteenth,
+    /// Ninth added to the chord without a seventh
+    Add9,
+    /// Eleventh added to the chord without a seventh
+    Add11,
+    /// Sixth and ninth without a seventh
+    SixNine,
 }
 
 impl Extension {
-    fn get_semitones(&self) -> i8 {
+    /// Semitones above the root of the tones the extension adds
+    fn get_semitones(&self) -> &'static [i8] {
         use Extension::*;
         match self {
-            FlatSixth => 8,
-            Sixth => 9,
-            MinorSeventh => 10,
-            MajorSeventh => 11,
-            FlatNinth => 13,
-            Ninth => 14,
-            SharpNinth => 15,
-            FlatEleventh => 16,
-            Eleventh => 17,
-            SharpEleventh => 18,
-            FlatThirteenth => 20,
-            Thirteenth => 21,
-            SharpThirteenth => 22,
+            FlatSixth => &[8],
+            Sixth => &[9],
+            MinorSeventh => &[10],
+            MajorSeventh => &[11],
+            FlatNinth => &[13],
+            Ninth => &[14],
+            SharpNinth => &[15],
+            FlatEleventh => &[16],
+            Eleventh => &[17],
+            SharpEleventh => &[18],
+            FlatThirteenth => &[20],
+            Thirteenth => &[21],
+            SharpThirteenth => &[22],
+            Add9 => &[14],
+            Add11 => &[17],
+            SixNine => &[9, 14],
         }
     }
+
+    fn is_seventh(&self) -> bool {
+        matches!(self, Extension::MinorSeventh | Extension::MajorSeventh)
+    }
+
+    /// Whether the extension is a tension built on a seventh chord, rather than an added tone
+    fn implies_seventh(&self) -> bool {
+        use Extension::*;
+        matches!(
+            self,
+            FlatNinth
+                | Ninth
+                | SharpNinth
+                | FlatEleventh
+                | Eleventh
+                | SharpEleventh
+                | FlatThirteenth
+                | Thirteenth
+                | SharpThirteenth
+        )
+    }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+/// Add `extensions` to chord tones given in semitones above the root. Tensions imply a dominant
+/// seventh if the chord has no seventh yet, the natural eleventh is avoided over a major third,
+/// and tones whose pitch class is already in the chord aren't repeated.
+fn add_extensions(tones: &mut Vec<i16>, extensions: &[Extension]) {
+    let has_pitch_class = |tones: &[i16], tone: i16| tones.iter().any(|t| (t - tone) % 12 == 0);
+    let held_seventh = extensions.iter().any(Extension::is_seventh)
+        || has_pitch_class(tones, 10)
+        || has_pitch_class(tones, 11);
+    if !held_seventh && extensions.iter().any(Extension::implies_seventh) {
+        tones.push(10);
+    }
+    let major_third = tones.contains(&4);
+    for extension in extensions {
+        if *extension == Extension::Eleventh && major_third {
+            continue;
+        }
+        for &semitones in extension.get_semitones() {
+            let tone = i16::from(semitones);
+            if !has_pitch_class(tones, tone) {
+                tones.push(tone);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Inversion {
     Root,
     First,
@@ -77,6 +167,18 @@ pub enum Inversion {
     Third,
 }
 
+impl Inversion {
+    /// Number of chord tones raised an octave to reach this inversion
+    fn get_count(&self) -> usize {
+        match self {
+            Inversion::Root => 0,
+            Inversion::First => 1,
+            Inversion::Second => 2,
+            Inversion::Third => 3,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Modifier {
     Quality(Quality),
@@ -84,69 +186,42 @@ pub enum Modifier {
     Inversion(Inversion),
 }
 
+impl Modifier {
+    pub fn get_group(&self) -> ModifierGroup {
+        match self {
+            Modifier::Quality(_) => ModifierGroup::Quality,
+            Modifier::Extension(_) => ModifierGroup::Extension,
+            Modifier::Inversion(_) => ModifierGroup::Inversion,
+        }
+    }
+}
+
+/// The kinds of modifier, which can be latched separately
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModifierGroup {
+    Quality,
+    Extension,
+    Inversion,
+}
+
+impl ModifierGroup {
+    pub const ALL: [ModifierGroup; 3] = [
+        ModifierGroup::Quality,
+        ModifierGroup::Extension,
+        ModifierGroup::Inversion,
+    ];
+}
+
 /// Allow for both keyboard and MIDI input to select modifiers
 pub enum MappingInput<'a> {
-    Keycode(Keycode),
+    /// A key and whether it was pressed or released
+    Keycode(Keycode, bool),
     MidiMessage(MidiMessage<'a>),
 }
 
 pub trait ModifierMapping {
-    fn get_modifier(input: MappingInput) -> Option<(Modifier, bool)>;
-}
-
-// Example implementations
-pub struct KeyboardMapping;
-pub struct OPXYMapping;
-
-impl ModifierMapping for KeyboardMapping {
-    fn get_modifier(input: MappingInput) -> Option<(Modifier, bool)> {
-        match input {
-            MappingInput::Keycode(key) => match key {
-                Keycode::Numpad7 => Some((Modifier::Quality(Quality::Diminished), true)),
-                Keycode::Numpad8 => Some((Modifier::Quality(Quality::Minor), true)),
-                Keycode::Numpad9 => Some((Modifier::Quality(Quality::Major), true)),
-                Keycode::NumpadSubtract => Some((Modifier::Quality(Quality::Augmented), true)),
-                Keycode::Numpad4 => Some((Modifier::Extension(Extension::Sixth), true)),
-                Keycode::Numpad5 => Some((Modifier::Extension(Extension::MinorSeventh), true)),
-                Keycode::Numpad6 => Some((Modifier::Extension(Extension::MajorSeventh), true)),
-                Keycode::NumpadAdd => Some((Modifier::Extension(Extension::Ninth), true)),
-                Keycode::Numpad1 => Some((Modifier::Inversion(Inversion::Root), true)),
-                Keycode::Numpad2 => Some((Modifier::Inversion(Inversion::First), true)),
-                Keycode::Numpad3 => Some((Modifier::Inversion(Inversion::Second), true)),
-                Keycode::NumpadEnter => Some((Modifier::Inversion(Inversion::Third), true)),
-                _ => None,
-            },
-            _ => None,
-        }
-    }
-}
-
-impl ModifierMapping for OPXYMapping {
-    fn get_modifier(input: MappingInput) -> Option<(Modifier, bool)> {
-        match input {
-            MappingInput::MidiMessage(msg) => match msg {
-                MidiMessage::ControlChange(_, function, value) => match u8::from(function.0) {
-                    7 => Some((Modifier::Quality(Quality::Major), u8::from(value) > 0)),
-                    8 => Some((Modifier::Quality(Quality::Minor), u8::from(value) > 0)),
-                    9 => Some((Modifier::Quality(Quality::Diminished), u8::from(value) > 0)),
-                    10 => Some((Modifier::Quality(Quality::Augmented), u8::from(value) > 0)),
-                    61 => Some((Modifier::Extension(Extension::Sixth), u8::from(value) > 0)),
-                    62 => Some((
-                        Modifier::Extension(Extension::MinorSeventh),
-                        u8::from(value) > 0,
-                    )),
-                    63 => Some((
-                        Modifier::Extension(Extension::MajorSeventh),
-                        u8::from(value) > 0,
-                    )),
-                    64 => Some((Modifier::Extension(Extension::Ninth), u8::from(value) > 0)),
-                    _ => None,
-                },
-                _ => None,
-            }, // implement MIDI mapping logic here
-            _ => None,
-        }
-    }
+    /// Get the modifiers an input presses or releases
+    fn get_modifiers(&self, input: MappingInput) -> Vec<(Modifier, bool)>;
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -154,6 +229,17 @@ pub struct ModifierStack {
     qualities: Vec<Quality>,
     extensions: Vec<Extension>,
     inversions: Vec<Inversion>,
+    combinations: Vec<Combination>,
+    /// Groups whose modifiers stay on after release
+    latched: Vec<ModifierGroup>,
+}
+
+/// Modifiers that, when held together, are played as other modifiers, e.g. major and minor
+/// together as sus4
+#[derive(Debug, Clone, PartialEq)]
+pub struct Combination {
+    pub held: Vec<Modifier>,
+    pub result: Vec<Modifier>,
 }
 
 impl ModifierStack {
@@ -162,10 +248,90 @@ impl ModifierStack {
             qualities: vec![],
             extensions: vec![],
             inversions: vec![],
+            combinations: vec![],
+            latched: vec![],
         }
     }
 
+    pub fn set_combinations(&mut self, combinations: Vec<Combination>) {
+        self.combinations = combinations;
+    }
+
+    /// Get the modifiers to play, with every fully held combination replaced by its result.
+    /// Larger combinations are applied first, and each held modifier is used by at most one.
+    fn resolve(&self) -> ModifierStack {
+        let mut resolved = ModifierStack {
+            combinations: vec![],
+            ..self.clone()
+        };
+        let mut combinations: Vec<&Combination> = self.combinations.iter().collect();
+        combinations.sort_by_key(|c| std::cmp::Reverse(c.held.len()));
+        let mut results = vec![];
+        for combination in combinations {
+            if !combination.held.is_empty() && combination.held.iter().all(|&m| resolved.is_held(m))
+            {
+                for &modifier in &combination.held {
+                    resolved.set(modifier, false);
+                }
+                results.extend_from_slice(&combination.result);
+            }
+        }
+        for modifier in results {
+            resolved.set(modifier, true);
+        }
+        resolved
+    }
+
+    fn is_held(&self, modifier: Modifier) -> bool {
+        match modifier {
+            Modifier::Quality(q) => self.qualities.contains(&q),
+            Modifier::Extension(e) => self.extensions.contains(&e),
+            Modifier::Inversion(i) => self.inversions.contains(&i),
+        }
+    }
+
+    /// Press or release a modifier. In a latched group, pressing a modifier replaces the group's
+    /// modifier, or turns it off if it was already on, and releases are ignored.
     pub fn update(&mut self, modifier: Modifier, is_pressed: bool) {
+        let group = modifier.get_group();
+        if !self.latched.contains(&group) {
+            self.set(modifier, is_pressed);
+        } else if is_pressed {
+            let was_held = self.is_held(modifier);
+            self.clear_group(group);
+            if !was_held {
+                self.set(modifier, true);
+            }
+        }
+    }
+
+    /// Latch or unlatch a group. Unlatching releases the group's modifiers, since their releases
+    /// were ignored.
+    pub fn set_latched(&mut self, group: ModifierGroup, latched: bool) {
+        self.latched.retain(|&g| g != group);
+        if latched {
+            self.latched.push(group);
+        } else {
+            self.clear_group(group);
+        }
+    }
+
+    /// Release every modifier
+    pub fn clear(&mut self) {
+        for group in ModifierGroup::ALL {
+            self.clear_group(group);
+        }
+    }
+
+    fn clear_group(&mut self, group: ModifierGroup) {
+        match group {
+            ModifierGroup::Quality => self.qualities.clear(),
+            ModifierGroup::Extension => self.extensions.clear(),
+            ModifierGroup::Inversion => self.inversions.clear(),
+        }
+    }
+
+    fn set(&mut self, modifier: Modifier, is_pressed: bool) {
         match modifier {
             Modifier::Quality(q) => self.update_quality(q, is_pressed),
             Modifier::Extension(e) => self.update_extension(e, is_pressed),
@@ -197,53 +363,437 @@ impl ModifierStack {
         }
     }
 
-    pub fn get_notes(&self, root: Note) -> Vec<Note> {
-        let mut notes = Vec::new();
-        if let Some(triad) = self.qualities.last() {
-            notes.push(root.step(triad.get_triad().third).unwrap());
-            notes.push(root.step(triad.get_triad().fifth).unwrap());
+    /// Whether a quality is held, overriding the key's chords
+    pub fn has_quality(&self) -> bool {
+        if !self.combinations.is_empty() {
+            return self.resolve().has_quality();
+        }
+        !self.qualities.is_empty()
+    }
+
+    /// Get the notes of the chord built on `root`, including the root itself, in ascending order.
+    /// Tones outside the MIDI range are placed according to `range`.
+    pub fn get_notes(&self, root: Note, voicing: Voicing, range: RangePolicy) -> Vec<Note> {
+        if !self.combinations.is_empty() {
+            return self.resolve().get_notes(root, voicing, range);
+        }
+        let root_value = i16::from(u8::from(root));
+        let mut tones = vec![0];
+        if let Some(quality) = self.qualities.last() {
+            tones.extend(quality.get_tones().iter().map(|&tone| i16::from(tone)));
+        }
+        add_extensions(&mut tones, &self.extensions);
+        let values: Vec<i16> = tones.iter().map(|tone| root_value + tone).collect();
+        let root = range.place(root_value).unwrap_or(root);
+        let mut notes = range.place_all(&values);
+        if let Some(inversion) = self.inversions.last() {
+            apply_inversion(&mut notes, root, *inversion);
+        }
+        voicing.apply(&mut notes, root);
+        range.confine(&notes)
+    }
+
+    /// Get the notes of the chord built on `root` within `key`. If a quality is held, the chord is
+    /// built chromatically as in `get_notes`; otherwise the diatonic triad is used, or the diatonic
+    /// seventh chord if a seventh or a tension that implies one is held. Natural tensions and
+    /// added tones are taken from the key, and only altered ones are added chromatically.
+    pub fn get_notes_in_key(
+        &self,
+        root: Note,
+        key: &Key,
+        rule: NonScaleRule,
+        voicing: Voicing,
+        range: RangePolicy,
+    ) -> Vec<Note> {
+        if !self.combinations.is_empty() {
+            return self
+                .resolve()
+                .get_notes_in_key(root, key, rule, voicing, range);
         }
-        for &extension in self.extensions.iter() {
-            notes.push(root.step(extension.get_semitones()).unwrap());
+        if self.has_quality() {
+            return self.get_notes(root, voicing, range);
         }
+        let seventh = self
+            .extensions
+            .iter()
+            .any(|e| e.is_seventh() || e.implies_seventh());
+        let height = if seventh { 4 } else { 3 };
+        // the key's notes stop at the top of the MIDI range, so build the chord an octave down
+        // where every tone exists and raise it back
+        let (base, octave) = match root.step(-12) {
+            Ok(lowered) => (lowered, 12),
+            Err(_) => (root, 0),
+        };
+        // stack every degree so natural tensions come from the key too
+        let stack = match key.scale.get_stacking() {
+            Stacking::Thirds => key.get_diatonic_chord(base, 7, rule),
+            Stacking::Fourths => key.get_diatonic_chord(base, height, rule),
+        };
+        let Some(&chord_base) = stack.first() else {
+            return vec![root];
+        };
+        let root_value = i16::from(u8::from(chord_base)) + octave;
+        let stack: Vec<i16> = stack
+            .iter()
+            .map(|n| i16::from(u8::from(*n)) - i16::from(u8::from(chord_base)))
+            .collect();
+        let mut tones: Vec<i16> = stack.iter().take(height).copied().collect();
+        // altered tensions, and natural ones the stack doesn't reach, are added chromatically
+        let mut chromatic = vec![];
+        for extension in &self.extensions {
+            // positions in the stack of thirds, and the octave the tone is moved by
+            let degrees: &[(usize, i16)] = match extension {
+                // the seventh is the key's own
+                Extension::MinorSeventh | Extension::MajorSeventh => &[],
+                Extension::Ninth | Extension::Add9 => &[(4, 0)],
+                Extension::Eleventh | Extension::Add11 => &[(5, 0)],
+                Extension::Thirteenth => &[(6, 0)],
+                Extension::Sixth => &[(6, -12)],
+                Extension::SixNine => &[(6, -12), (4, 0)],
+                altered => {
+                    chromatic.push(*altered);
+                    continue;
+                }
+            };
+            if key.scale.get_stacking() == Stacking::Fourths
+                || degrees.iter().any(|(degree, _)| *degree >= stack.len())
+            {
+                chromatic.push(*extension);
+                continue;
+            }
+            for (degree, octave) in degrees {
+                let tone = stack[*degree] + octave;
+                // the natural eleventh is avoided over a major third
+                let avoided = *extension == Extension::Eleventh && tone == 17 && tones.contains(&4);
+                if !avoided && !tones.iter().any(|t| (t - tone) % 12 == 0) {
+                    tones.push(tone);
+                }
+            }
+        }
+        add_extensions(&mut tones, &chromatic);
+        let values: Vec<i16> = tones.iter().map(|tone| root_value + tone).collect();
+        let chord_root = range.place(root_value).unwrap_or(root);
+        let mut notes = range.place_all(&values);
+        if let Some(inversion) = self.inversions.last() {
+            apply_inversion(&mut notes, chord_root, *inversion);
+        }
+        voicing.apply(&mut notes, chord_root);
+        range.confine(&notes)
+    }
+}
+
+/// Raise the lowest chord tones an octave. Only tones within the first octave above the root
+/// can be inverted, so the third inversion needs a sixth or seventh; if the chord doesn't have
+/// enough tones, the highest available inversion is used instead.
+fn apply_inversion(notes: &mut [Note], root: Note, inversion: Inversion) {
+    let available = notes
+        .iter()
+        .filter(|&&n| (0..12).contains(&(i16::from(u8::from(n)) - i16::from(u8::from(root)))))
+        .count()
+        .saturating_sub(1);
+    let count = inversion.get_count().min(available);
+    for note in notes.iter_mut().take(count) {
+        // leave the note where it is if raising it would exceed the MIDI range
+        if let Ok(raised) = note.step(12) {
+            *note = raised;
+        }
+    }
+    notes.sort();
+}
+
+/// How chord tones outside the MIDI note range are handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RangePolicy {
+    /// Leave the tone out of the chord
+    Drop,
+    /// Move the tone by octaves until it is within the MIDI range
+    Fold,
+    /// Move every tone by octaves into the register, or to its nearest end if the register is
+    /// narrower than an octave
+    Clamp { low: Note, high: Note },
+}
+
+impl RangePolicy {
+    /// Get the note for a tone given in semitones above the lowest MIDI note, or None if it is
+    /// dropped
+    pub fn place(&self, value: i16) -> Option<Note> {
+        let (low, high) = match self {
+            RangePolicy::Drop => {
+                return u8::try_from(value)
+                    .ok()
+                    .and_then(|v| Note::try_from(v).ok());
+            }
+            RangePolicy::Fold => (0, 127),
+            RangePolicy::Clamp { low, high } => {
+                (i16::from(u8::from(*low)), i16::from(u8::from(*high)))
+            }
+        };
+        let mut value = value;
+        while value > high {
+            value -= 12;
+        }
+        while value < low {
+            value += 12;
+        }
+        Some(Note::from_u8_lossy(value.clamp(low, high) as u8))
+    }
+
+    /// Move notes that inversions or voicings took out of a clamped register back into it
+    fn confine(&self, notes: &[Note]) -> Vec<Note> {
+        let values: Vec<i16> = notes.iter().map(|n| i16::from(u8::from(*n))).collect();
+        self.place_all(&values)
+    }
+
+    /// Place every tone, in ascending order without duplicates
+    fn place_all(&self, values: &[i16]) -> Vec<Note> {
+        let mut notes: Vec<Note> = values.iter().filter_map(|&v| self.place(v)).collect();
+        notes.sort();
+        notes.dedup();
         notes
     }
 }
 
-impl fmt::Display for ModifierStack {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let qualities = self.qualities.last().map_or("", |q| match q {
-            Quality::Major => "maj",
-            Quality::Minor => "min",
-            Quality::Diminished => "dim",
-            Quality::Augmented => "aug",
-            Quality::Sus2 => "sus2",
-            Quality::Sus4 => "sus4",
-        });
-
-        let extensions = self.extensions.last().map_or("", |e| match e {
-            Extension::Sixth => "6",
-            Extension::MinorSeventh => "7",
-            Extension::MajorSeventh => "maj7",
-            Extension::Ninth => "9",
-            Extension::FlatSixth => "b6",
-            Extension::FlatNinth => "b9",
-            Extension::FlatEleventh => "b11",
-            Extension::Eleventh => "11",
-            Extension::SharpNinth => "#9",
-            Extension::SharpEleventh => "#11",
-            Extension::FlatThirteenth => "b13",
-            Extension::Thirteenth => "13",
-            Extension::SharpThirteenth => "#13",
-        });
-
-        let inversion = self.inversions.last().map_or("", |i| match i {
-            Inversion::Root => "",
-            Inversion::First => "/1",
-            Inversion::Second => "/2",
-            Inversion::Third => "/3",
-        });
-
-        write!(f, "{}{}{}", qualities, extensions, inversion)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::theory::Scale;
+
+    const QUALITIES: [Option<Quality>; 7] = [
+        None,
+        Some(Quality::Diminished),
+        Some(Quality::Minor),
+        Some(Quality::Major),
+        Some(Quality::Augmented),
+        Some(Quality::Sus2),
+        Some(Quality::Sus4),
+    ];
+
+    const EXTENSIONS: [Extension; 16] = [
+        Extension::FlatSixth,
+        Extension::Sixth,
+        Extension::MinorSeventh,
+        Extension::MajorSeventh,
+        Extension::FlatNinth,
+        Extension::Ninth,
+        Extension::SharpNinth,
+        Extension::FlatEleventh,
+        Extension::Eleventh,
+        Extension::SharpEleventh,
+        Extension::FlatThirteenth,
+        Extension::Thirteenth,
+        Extension::SharpThirteenth,
+        Extension::Add9,
+        Extension::Add11,
+        Extension::SixNine,
+    ];
+
+    const INVERSIONS: [Inversion; 4] = [
+        Inversion::Root,
+        Inversion::First,
+        Inversion::Second,
+        Inversion::Third,
+    ];
+
+    const RANGES: [RangePolicy; 3] = [
+        RangePolicy::Drop,
+        RangePolicy::Fold,
+        // narrower than an octave, so every tone has to be moved to an end
+        RangePolicy::Clamp {
+            low: Note::C4,
+            high: Note::G4,
+        },
+    ];
+
+    /// Every extension on its own, and every pair of extensions. Larger sets are left out to keep
+    /// the sweep fast; they add tones the same way pairs do.
+    fn extension_sets(pairs: bool) -> Vec<Vec<Extension>> {
+        let mut sets = vec![vec![]];
+        for (i, &first) in EXTENSIONS.iter().enumerate() {
+            sets.push(vec![first]);
+            if pairs {
+                for &second in &EXTENSIONS[i + 1..] {
+                    sets.push(vec![first, second]);
+                }
+            }
+        }
+        sets
+    }
+
+    fn stack(
+        quality: Option<Quality>,
+        extensions: &[Extension],
+        inversion: Inversion,
+    ) -> ModifierStack {
+        let mut stack = ModifierStack::new();
+        if let Some(quality) = quality {
+            stack.update(Modifier::Quality(quality), true);
+        }
+        for &extension in extensions {
+            stack.update(Modifier::Extension(extension), true);
+        }
+        stack.update(Modifier::Inversion(inversion), true);
+        stack
+    }
+
+    fn pitch_classes(notes: &[Note]) -> Vec<u8> {
+        let mut classes: Vec<u8> = notes.iter().map(|n| u8::from(*n) % 12).collect();
+        classes.sort();
+        classes.dedup();
+        classes
+    }
+
+    /// Check the chord built on `root` against the same chord built in the middle of the range.
+    /// Clamped chords stay in the register, folded chords keep every pitch class, and dropping
+    /// tones never adds one.
+    fn check_range(stack: &ModifierStack, root: Note, voicing: Voicing, range: RangePolicy) {
+        let notes = stack.get_notes(root, voicing, range);
+        let middle_root = Note::from_u8_lossy(u8::from(root) % 12 + 60);
+        assert!(!notes.is_empty() && notes.windows(2).all(|pair| pair[0] < pair[1]));
+        match range {
+            RangePolicy::Clamp { low, high } => {
+                assert!(
+                    notes.iter().all(|n| (low..=high).contains(n)),
+                    "{:?}",
+                    notes
+                );
+            }
+            RangePolicy::Fold => {
+                let middle = stack.get_notes(middle_root, voicing, range);
+                assert_eq!(pitch_classes(&notes), pitch_classes(&middle), "{:?}", root);
+            }
+            RangePolicy::Drop => {
+                // voicings that leave out tones may keep them when nothing else is left
+                let middle = stack.get_notes(middle_root, Voicing::Close, range);
+                let kept = pitch_classes(&middle);
+                assert!(
+                    pitch_classes(&notes).iter().all(|p| kept.contains(p)),
+                    "{:?}",
+                    root
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn chords_stay_in_range_for_every_root_and_extension_pair() {
+        let key = Key::new(Note::C4, Scale::Ionian);
+        let rules = [NonScaleRule::Snap, NonScaleRule::Borrowed];
+        for quality in QUALITIES {
+            for extensions in extension_sets(true) {
+                let stack = stack(quality, &extensions, Inversion::Root);
+                for root in (0..=127).map(Note::from_u8_lossy) {
+                    for range in RANGES {
+                        check_range(&stack, root, Voicing::Close, range);
+                        for rule in rules {
+                            let notes =
+                                stack.get_notes_in_key(root, &key, rule, Voicing::Close, range);
+                            assert!(!notes.is_empty() && notes.windows(2).all(|p| p[0] < p[1]));
+                            if let RangePolicy::Clamp { low, high } = range {
+                                assert!(notes.iter().all(|n| (low..=high).contains(n)));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn every_voicing_and_inversion_stays_in_range() {
+        let key = Key::new(Note::C4, Scale::Ionian);
+        for quality in QUALITIES {
+            for extensions in extension_sets(false) {
+                for inversion in INVERSIONS {
+                    let stack = stack(quality, &extensions, inversion);
+                    for voicing in Voicing::ALL {
+                        // every pitch class at both ends of the range and in the middle
+                        let roots = (0..12).chain(54..66).chain(116..=127);
+                        for root in roots.map(Note::from_u8_lossy) {
+                            for range in RANGES {
+                                check_range(&stack, root, voicing, range);
+                                let notes = stack.get_notes_in_key(
+                                    root,
+                                    &key,
+                                    NonScaleRule::Snap,
+                                    voicing,
+                                    range,
+                                );
+                                assert!(!notes.is_empty());
+                                if let RangePolicy::Clamp { low, high } = range {
+                                    assert!(notes.iter().all(|n| (low..=high).contains(n)));
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn natural_tensions_stay_in_the_key() {
+        use Extension::*;
+        let key = Key::new(Note::C4, Scale::Ionian);
+        let scale_notes = key.get_notes();
+        let natural = [Ninth, Eleventh, Thirteenth, Add9, Add11, Sixth, SixNine];
+        for extension in natural {
+            for root in scale_notes
+                .iter()
+                .filter(|n| (Note::C4..Note::C5).contains(*n))
+            {
+                let mut stack = ModifierStack::new();
+                stack.update(Modifier::Extension(extension), true);
+                let notes = stack.get_notes_in_key(
+                    *root,
+                    &key,
+                    NonScaleRule::Snap,
+                    Voicing::Close,
+                    RangePolicy::Fold,
+                );
+                assert!(
+                    notes.iter().all(|n| scale_notes.contains(n)),
+                    "{:?} on {:?}: {:?}",
+                    extension,
+                    root,
+                    notes
+                );
+            }
+        }
+
+        let values = |stack: &ModifierStack, root: Note| -> Vec<u8> {
+            stack
+                .get_notes_in_key(
+                    root,
+                    &key,
+                    NonScaleRule::Snap,
+                    Voicing::Close,
+                    RangePolicy::Fold,
+                )
+                .iter()
+                .map(|n| u8::from(*n))
+                .collect()
+        };
+        let mut stack = ModifierStack::new();
+        stack.update(Modifier::Extension(Ninth), true);
+        // E G B D F, not F#
+        assert_eq!(values(&stack, Note::E4), [64, 67, 71, 74, 77]);
+        // B D F A C, not C#
+        assert_eq!(values(&stack, Note::B4), [71, 74, 77, 81, 84]);
+        // an altered tension is still added as held: G B D F Ab
+        stack.update(Modifier::Extension(Ninth), false);
+        stack.update(Modifier::Extension(FlatNinth), true);
+        assert_eq!(values(&stack, Note::G4), [67, 71, 74, 77, 80]);
+    }
+
+    #[test]
+    fn fold_keeps_every_tone() {
+        let mut stack = ModifierStack::new();
+        stack.update(Modifier::Quality(Quality::Major), true);
+        stack.update(Modifier::Extension(Extension::Thirteenth), true);
+        let notes = stack.get_notes(Note::G9, Voicing::Close, RangePolicy::Fold);
+        let dropped = stack.get_notes(Note::G9, Voicing::Close, RangePolicy::Drop);
+        // root, third, fifth, seventh and thirteenth
+        assert_eq!(notes.len(), 5);
+        assert_eq!(dropped, vec![Note::G9]);
     }
 }
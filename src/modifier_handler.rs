@@ -1,72 +1,46 @@
-use crate::midi::get_midi_in_port;
-use crate::modifier::{MappingInput, ModifierMapping, ModifierStack, OPXYMapping};
-use midir::MidiInput;
-use std::error::Error;
+use crate::learn;
+use crate::mapping::Mappings;
+use crate::modifier::{MappingInput, ModifierMapping};
+use crate::state::GlobalState;
 use std::sync::Arc;
 use tokio::sync::RwLock;
-use tokio::sync::mpsc;
-use tokio::task::JoinHandle;
 use wmidi::MidiMessage;
 
+/// Update the modifier stack from a message sent by the device on `port_name`, or bind the
+/// message in learn mode. Returns whether the message was consumed and shouldn't be handled
+/// further.
 pub async fn handle_modifiers(
-    stack: Arc<RwLock<ModifierStack>>,
-) -> Result<JoinHandle<()>, Box<dyn Error>> {
-    let (callback_tx, mut callback_rx) = mpsc::channel::<Vec<u8>>(1024); // Increased buffer size
-
-    let midi_in = MidiInput::new("Poorkid Input")?;
-    let input_port = get_midi_in_port()?;
-
-    // Create connection and move ownership of tx to the callback
-    let _conn = midi_in.connect(
-        &input_port,
-        "midi-input",
-        move |_stamp, message, _| {
-            println!("Callback received message. Sending to receiver.");
-            if let Err(e) = callback_tx.try_send(message.to_vec()) {
-                println!("Failed to send message from callback: {:?}", e);
-            }
-        },
-        (),
-    )?;
-
-    let input_task = tokio::spawn(async move {
-        println!("MIDI input task started");
-        // move ownership of conn to the async task, otherwise it will be closed when returning
-        let conn = _conn;
-
-        let result: Result<(), Box<dyn Error + Send + Sync>> = async {
-            while let Some(message) = callback_rx.recv().await {
-                println!("Received message, sending to main receiver");
-                match MidiMessage::from_bytes(&message) {
-                    Ok(midi_message) => {
-                        println!("Sending MIDI message outer: {:?}", midi_message);
-                        if let Some((modifier, pressed)) =
-                            OPXYMapping::get_modifier(MappingInput::MidiMessage(midi_message))
-                        {
-                            println!("Received modifier: {:?}", modifier);
-                            let mut data = stack.write().await;
-                            data.update(modifier, pressed);
-                        }
-                    }
-                    Err(e) => {
-                        let err_msg = format!(
-                            "Failed to parse MIDI message: {:?} - Error: {:?}",
-                            message, e
-                        );
-                        println!("{}", err_msg);
-                    }
-                }
-            }
-            Ok(())
+    state: &Arc<RwLock<GlobalState>>,
+    mappings: &Arc<RwLock<Mappings>>,
+    port_name: &str,
+    midi_message: &MidiMessage<'_>,
+) -> bool {
+    // in learn mode, bind the next press to the learn target instead
+    let learning = state.read().await.learn;
+    if let (Some(target), Some(trigger)) = (learning, learn::get_trigger(midi_message)) {
+        state.write().await.learn = None;
+        let mut mappings = mappings.write().await;
+        match learn::bind(&mut mappings, port_name, target, trigger) {
+            Ok(()) => println!("Bound {:?} to {:?}", trigger, target),
+            Err(e) => println!("Could not bind {:?}: {}", trigger, e),
         }
-        .await;
-
-        if let Err(e) = result {
-            println!("MIDI input task error: {:?}", e);
-        }
-
-        conn.close();
-    });
-
-    Ok(input_task)
+        return true;
+    }
+
+    let (modifiers, forward) = match mappings.read().await.for_port(port_name) {
+        Some(mapping) => (
+            mapping.get_modifiers(MappingInput::MidiMessage(midi_message.clone())),
+            mapping.forward_modifiers,
+        ),
+        None => return false,
+    };
+    if modifiers.is_empty() {
+        return false;
+    }
+    let mut state = state.write().await;
+    for (modifier, pressed) in modifiers {
+        println!("Received modifier: {:?}", modifier);
+        state.modifier_state.update(modifier, pressed);
+    }
+    !forward
 }
@@ -0,0 +1,160 @@
+use crate::mapping::Mappings;
+use crate::midi::{PortSelector, find_midi_out_port, get_midi_in_port};
+use crate::midi_in::{ChordStatus, transform_message};
+use crate::modifier_handler::handle_modifiers;
+use crate::state::GlobalState;
+use midir::{MidiInput, MidiInputConnection, MidiInputPort, MidiOutput, MidiOutputConnection};
+use std::error::Error;
+use std::sync::Arc;
+use tokio::sync::{RwLock, mpsc};
+use tokio::task::JoinHandle;
+use wmidi::MidiMessage;
+
+/// What messages from an input port are used for
+#[derive(Debug, Clone, Copy)]
+struct Route {
+    /// Play notes through the chord engine
+    notes: bool,
+    /// Update modifiers and the control surface
+    controls: bool,
+}
+
+/// Open one connection per input port and dispatch each message, parsed once, to the modifier
+/// mapping, the control surface and the chord engine. Messages consumed by a modifier or
+/// control aren't played as notes.
+pub async fn run_router(
+    state: Arc<RwLock<GlobalState>>,
+    mappings: Arc<RwLock<Mappings>>,
+    status: Arc<RwLock<ChordStatus>>,
+    tx: mpsc::Sender<Vec<u8>>,
+    note_input: Option<PortSelector>,
+    control_input: Option<PortSelector>,
+) -> Result<JoinHandle<()>, Box<dyn Error>> {
+    // Create channel for communication between MIDI callbacks and the async task
+    let (callback_tx, mut callback_rx) = mpsc::channel::<(Route, Vec<u8>)>(1024);
+
+    let note_port = get_midi_in_port(note_input.as_ref())?;
+    let control_port = get_midi_in_port(control_input.as_ref())?;
+    let note_port_name = get_port_name(&note_port)?;
+    let control_port_name = get_port_name(&control_port)?;
+
+    let mut connections = Vec::new();
+    if note_port_name == control_port_name {
+        let route = Route {
+            notes: true,
+            controls: true,
+        };
+        connections.push(connect(&note_port, route, callback_tx)?);
+    } else {
+        let note_route = Route {
+            notes: true,
+            controls: false,
+        };
+        let control_route = Route {
+            notes: false,
+            controls: true,
+        };
+        connections.push(connect(&note_port, note_route, callback_tx.clone())?);
+        connections.push(connect(&control_port, control_route, callback_tx)?);
+    }
+
+    // send feedback to the controller's output port, if it has one
+    let mut feedback: Option<MidiOutputConnection> = match find_midi_out_port(&control_port_name) {
+        Ok(output_port) => Some(
+            MidiOutput::new("Poorkid Control Output")?
+                .connect(&output_port, "control-feedback")
+                .map_err(|e| e.to_string())?,
+        ),
+        Err(e) => {
+            println!("Control surface feedback disabled: {}", e);
+            None
+        }
+    };
+
+    let router_task = tokio::spawn(async move {
+        println!("MIDI input task started");
+        // move ownership of the connections to the async task, otherwise they will be closed
+        // when returning
+        let connections = connections;
+
+        // bring the controller in sync with the initial state
+        let surface = mappings.read().await.get_controls(&control_port_name);
+        if let Some(surface) = surface {
+            send_feedback(&mut feedback, surface.get_feedback(&*state.read().await));
+        }
+
+        while let Some((route, message)) = callback_rx.recv().await {
+            let midi_message = match MidiMessage::from_bytes(&message) {
+                Ok(midi_message) => midi_message,
+                Err(e) => {
+                    println!(
+                        "Failed to parse MIDI message: {:?} - Error: {:?}",
+                        message, e
+                    );
+                    continue;
+                }
+            };
+
+            if route.controls {
+                if handle_modifiers(&state, &mappings, &control_port_name, &midi_message).await {
+                    continue;
+                }
+                // the layout may change while learning controls
+                let surface = mappings.read().await.get_controls(&control_port_name);
+                let echo = match surface {
+                    Some(surface) => surface.handle(&mut *state.write().await, &midi_message),
+                    None => None,
+                };
+                if let Some(echo) = echo {
+                    send_feedback(&mut feedback, echo);
+                    continue;
+                }
+            }
+
+            if route.notes {
+                transform_message(state.clone(), status.clone(), tx.clone(), &midi_message).await;
+            }
+        }
+
+        println!("MIDI input task ended");
+        for conn in connections {
+            conn.close();
+        }
+    });
+
+    Ok(router_task)
+}
+
+fn get_port_name(port: &MidiInputPort) -> Result<String, Box<dyn Error>> {
+    Ok(MidiInput::new("Poorkid Input")?.port_name(port)?)
+}
+
+fn connect(
+    port: &MidiInputPort,
+    route: Route,
+    callback_tx: mpsc::Sender<(Route, Vec<u8>)>,
+) -> Result<MidiInputConnection<()>, Box<dyn Error>> {
+    let midi_in = MidiInput::new("Poorkid Input")?;
+    // Create connection and move ownership of tx to the callback
+    let conn = midi_in.connect(
+        port,
+        "midi-input",
+        move |_stamp, message, _| {
+            if let Err(e) = callback_tx.try_send((route, message.to_vec())) {
+                println!("Failed to send message from callback: {:?}", e);
+            }
+        },
+        (),
+    )?;
+    Ok(conn)
+}
+
+fn send_feedback(feedback: &mut Option<MidiOutputConnection>, messages: Vec<MidiMessage>) {
+    if let Some(feedback) = feedback.as_mut() {
+        for message in messages {
+            if let Err(e) = feedback.send(&message.to_vec()) {
+                println!("Error sending control feedback: {:?}", e);
+            }
+        }
+    }
+}
@@ -1,31 +1,185 @@
-use crate::modifier::ModifierStack;
-use crate::theory::{Key, Scale};
-use wmidi::Note;
+use crate::modifier::{Modifier, ModifierStack, RangePolicy};
+use crate::theory::{Key, NonScaleRule, Quantize, Scale};
+use crate::voice_leading::VoiceLeading;
+use crate::voicing::Voicing;
+use wmidi::{Channel, Note, U7};
 
 // Core state structs
 #[derive(Debug, Clone)]
 pub struct GlobalState {
     pub key: Key,
+    /// Scales the scale control steps through
+    pub scales: Vec<Scale>,
+    pub harmony: Harmony,
+    /// How played notes are moved into the key, if at all
+    pub quantize: Option<Quantize>,
+    pub voicing: Voicing,
+    /// How chord tones beyond the MIDI note range are handled
+    pub range: RangePolicy,
     pub bpm: f32,
     pub perform: Perform,
     pub perform_params: PerformState,
     pub modifier_state: ModifierStack,
     pub active_notes: Vec<Note>,
     pub page: Page,
+    /// What the next control message is bound to, while in learn mode
+    pub learn: Option<LearnTarget>,
+    /// Register to voice-lead successive chords within, if enabled
+    pub voice_leading: Option<VoiceLeading>,
+    /// Separate bass voice, if enabled
+    pub bass: Option<BassVoice>,
 }
 
 impl GlobalState {
-    fn new() -> Self {
+    pub fn new() -> Self {
         Self {
             key: Key::new(Note::C4, Scale::Ionian),
+            scales: Scale::ALL.to_vec(),
+            harmony: Harmony::Chromatic,
+            quantize: None,
+            voicing: Voicing::Close,
+            range: RangePolicy::Fold,
             bpm: 120.0,
             perform: Perform::None,
             perform_params: PerformState::new(),
             modifier_state: ModifierStack::new(),
             page: Page::One,
             active_notes: Vec::new(),
+            learn: None,
+            voice_leading: None,
+            bass: None,
+        }
+    }
+
+    /// Get the chord for `root` from the held modifiers, according to the current harmony mode
+    pub fn get_chord(&self, root: Note) -> Vec<Note> {
+        self.build_chord(root, self.voicing)
+    }
+
+    /// Get the root of the chord played for `root`, which is another note when a note outside
+    /// the key snaps to a scale degree
+    pub fn get_chord_root(&self, root: Note) -> Note {
+        match self.harmony {
+            Harmony::Diatonic(rule) if !self.modifier_state.has_quality() => self
+                .key
+                .get_diatonic_chord(root, 1, rule)
+                .first()
+                .copied()
+                .unwrap_or(root),
+            _ => root,
+        }
+    }
+
+    /// Get the bass note for `root`: the lowest tone of the chord in close position, which is the
+    /// chord root unless an inversion is held, moved down by the bass voice's octaves
+    pub fn get_bass(&self, root: Note, bass: &BassVoice) -> Option<Note> {
+        let lowest = *self.build_chord(root, Voicing::Close).first()?;
+        lowest.step(-12 * bass.octaves as i8).ok()
+    }
+
+    fn build_chord(&self, root: Note, voicing: Voicing) -> Vec<Note> {
+        match self.harmony {
+            Harmony::Chromatic => self.modifier_state.get_notes(root, voicing, self.range),
+            Harmony::Diatonic(rule) => self
+                .modifier_state
+                .get_notes_in_key(root, &self.key, rule, voicing, self.range),
+        }
+    }
+
+    /// Track newly sounding notes, in the order they were played
+    pub fn add_active_notes(&mut self, notes: &[Note]) {
+        self.active_notes.extend_from_slice(notes);
+    }
+
+    /// Stop tracking released notes. Only one occurrence of each note is removed, since the same
+    /// note may belong to more than one held chord.
+    pub fn remove_active_notes(&mut self, notes: &[Note]) {
+        for note in notes {
+            if let Some(index) = self.active_notes.iter().position(|n| n == note) {
+                self.active_notes.remove(index);
+            }
+        }
+    }
+
+    /// Get the position of a control's current value within its range, and the size of the range
+    pub fn get_control_position(&self, control: RotaryControl) -> (usize, usize) {
+        match control {
+            RotaryControl::Root(_) => (usize::from(u8::from(self.key.root()) % 12), 12),
+            RotaryControl::Scale(_) => position_of(&self.scales, self.key.scale),
+            RotaryControl::Bpm(_) => (
+                (self.bpm.round() as usize).clamp(MIN_BPM, MAX_BPM) - MIN_BPM,
+                MAX_BPM - MIN_BPM + 1,
+            ),
+            RotaryControl::Perform(_) => position_of(&Perform::ALL, self.perform),
+            RotaryControl::Voicing(_) => position_of(&Voicing::ALL, self.voicing),
+            RotaryControl::PerformParam(param) => match self.perform_params.get_value(param) {
+                PerformParam::StrumSpacing(spacing) => (usize::from(spacing), 256),
+                PerformParam::StrumDirection(dir) => position_of(&StrumDirection::ALL, dir),
+                PerformParam::VelocityFalloff(falloff) => (usize::from(falloff.min(127)), 128),
+                PerformParam::ArpeggioDirection(dir) => position_of(&ArpeggioDirection::ALL, dir),
+                PerformParam::ArpeggioRate(rate) => position_of(&Rate::ALL, rate),
+                PerformParam::ArpeggioGate(gate) => (usize::from(gate.clamp(1, 100)) - 1, 100),
+                PerformParam::None => (0, 1),
+            },
         }
     }
+
+    /// Set a control to the value at `position` within its range
+    pub fn set_control_position(&mut self, control: RotaryControl, position: usize) {
+        let (_, count) = self.get_control_position(control);
+        let position = position.min(count - 1);
+        match control {
+            RotaryControl::Root(_) => {
+                self.key = Key::new(Note::from_u8_lossy(position as u8), self.key.scale)
+            }
+            RotaryControl::Scale(_) => self.key.scale = self.scales[position],
+            RotaryControl::Bpm(_) => self.bpm = (MIN_BPM + position) as f32,
+            RotaryControl::Perform(_) => self.perform = Perform::ALL[position],
+            RotaryControl::Voicing(_) => self.voicing = Voicing::ALL[position],
+            RotaryControl::PerformParam(param) => self.perform_params.update(match param {
+                PerformParam::StrumSpacing(_) => PerformParam::StrumSpacing(position as u8),
+                PerformParam::StrumDirection(_) => {
+                    PerformParam::StrumDirection(StrumDirection::ALL[position])
+                }
+                PerformParam::VelocityFalloff(_) => PerformParam::VelocityFalloff(position as u8),
+                PerformParam::ArpeggioDirection(_) => {
+                    PerformParam::ArpeggioDirection(ArpeggioDirection::ALL[position])
+                }
+                PerformParam::ArpeggioRate(_) => PerformParam::ArpeggioRate(Rate::ALL[position]),
+                PerformParam::ArpeggioGate(_) => PerformParam::ArpeggioGate(position as u8 + 1),
+                PerformParam::None => PerformParam::None,
+            }),
+        }
+    }
+
+    /// Move a control by `delta` steps. Enum values and the root wrap around; numeric values stop
+    /// at the ends of their range.
+    pub fn adjust_control(&mut self, control: RotaryControl, delta: i32) {
+        let (position, count) = self.get_control_position(control);
+        let target = position as i64 + i64::from(delta);
+        let position = if control.wraps() {
+            target.rem_euclid(count as i64)
+        } else {
+            target.clamp(0, count as i64 - 1)
+        };
+        self.set_control_position(control, position as usize);
+    }
+}
+
+const MIN_BPM: usize = 20;
+const MAX_BPM: usize = 300;
+
+/// Get the position of `value` in `all`, and the number of values
+fn position_of<T: PartialEq>(all: &[T], value: T) -> (usize, usize) {
+    (all.iter().position(|v| *v == value).unwrap_or(0), all.len())
+}
+
+/// Something a control can be bound to in learn mode
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LearnTarget {
+    Modifier(Modifier),
+    Control(RotaryControl),
+    PageButton,
 }
 
 // Rotary control enum
@@ -36,11 +190,46 @@ pub enum RotaryControl {
     Bpm(u16),
     Perform(Perform),
     PerformParam(PerformParam),
+    Voicing(Voicing),
+}
+
+impl RotaryControl {
+    fn is_same_control(&self, other: &RotaryControl) -> bool {
+        use std::mem::discriminant;
+        match (self, other) {
+            (RotaryControl::PerformParam(a), RotaryControl::PerformParam(b)) => {
+                discriminant(a) == discriminant(b)
+            }
+            _ => discriminant(self) == discriminant(other),
+        }
+    }
+
+    fn wraps(&self) -> bool {
+        match self {
+            RotaryControl::Root(_)
+            | RotaryControl::Scale(_)
+            | RotaryControl::Perform(_)
+            | RotaryControl::Voicing(_) => true,
+            RotaryControl::Bpm(_) => false,
+            RotaryControl::PerformParam(param) => matches!(
+                param,
+                PerformParam::StrumDirection(_)
+                    | PerformParam::ArpeggioDirection(_)
+                    | PerformParam::ArpeggioRate(_)
+            ),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct PerformState {
+    /// Milliseconds between strummed notes
     pub spacing: u8,
+    pub strum_direction: StrumDirection,
+    /// Velocity subtracted from each successive strummed note
+    pub velocity_falloff: u8,
+    /// Direction of the last strum, used to alternate strums
+    pub last_strum_down: bool,
     pub arpeggiator: ArpeggiatorState,
 }
 
@@ -48,15 +237,33 @@ impl PerformState {
     fn new() -> Self {
         Self {
             spacing: 20, // default value
+            strum_direction: StrumDirection::Up,
+            velocity_falloff: 0,
+            last_strum_down: false,
             arpeggiator: ArpeggiatorState::new(),
         }
     }
 
+    /// Get whether the next strum goes from the highest note down, flipping alternating strums
+    pub fn next_strum_down(&mut self) -> bool {
+        match self.strum_direction {
+            StrumDirection::Up => false,
+            StrumDirection::Down => true,
+            StrumDirection::Alternate => {
+                self.last_strum_down = !self.last_strum_down;
+                self.last_strum_down
+            }
+        }
+    }
+
     fn update(&mut self, param: PerformParam) {
         match param {
             PerformParam::StrumSpacing(value) => self.spacing = value,
+            PerformParam::StrumDirection(dir) => self.strum_direction = dir,
+            PerformParam::VelocityFalloff(value) => self.velocity_falloff = value,
             PerformParam::ArpeggioDirection(dir) => self.arpeggiator.direction = dir,
             PerformParam::ArpeggioRate(rate) => self.arpeggiator.rate = rate,
+            PerformParam::ArpeggioGate(gate) => self.arpeggiator.gate = gate,
             PerformParam::None => (),
         }
     }
@@ -64,10 +271,15 @@ impl PerformState {
     fn get_value(&self, param_type: PerformParam) -> PerformParam {
         match param_type {
             PerformParam::StrumSpacing(_) => PerformParam::StrumSpacing(self.spacing),
+            PerformParam::StrumDirection(_) => PerformParam::StrumDirection(self.strum_direction),
+            PerformParam::VelocityFalloff(_) => {
+                PerformParam::VelocityFalloff(self.velocity_falloff)
+            }
             PerformParam::ArpeggioDirection(_) => {
                 PerformParam::ArpeggioDirection(self.arpeggiator.direction)
             }
             PerformParam::ArpeggioRate(_) => PerformParam::ArpeggioRate(self.arpeggiator.rate),
+            PerformParam::ArpeggioGate(_) => PerformParam::ArpeggioGate(self.arpeggiator.gate),
             PerformParam::None => PerformParam::None,
         }
     }
@@ -77,7 +289,14 @@ impl PerformState {
 pub struct ArpeggiatorState {
     pub direction: ArpeggioDirection,
     pub rate: Rate,
+    /// Percentage of each step the note is held for
+    pub gate: u8,
+    /// Keep playing the last chord after its notes are released
+    pub latch: bool,
     pub index: usize,
+    /// Channel and velocity of the most recently played note
+    pub channel: Channel,
+    pub velocity: U7,
 }
 
 impl ArpeggiatorState {
@@ -85,12 +304,44 @@ impl ArpeggiatorState {
         Self {
             direction: ArpeggioDirection::Up,
             rate: Rate::Eighth,
+            gate: 50,
+            latch: true,
             index: 0,
+            channel: Channel::Ch1,
+            velocity: U7::from_u8_lossy(100),
+        }
+    }
+}
+
+/// A bass note played below each chord, so one key can drive a bass synth and a pad synth
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BassVoice {
+    pub channel: Channel,
+    /// Octaves below the chord's lowest close-position tone, 1 or 2
+    pub octaves: u8,
+    /// Leave the chord root's pitch class out of the chord
+    pub omit_from_chord: bool,
+}
+
+impl BassVoice {
+    pub fn new(channel: Channel) -> Self {
+        Self {
+            channel,
+            octaves: 1,
+            omit_from_chord: false,
         }
     }
 }
 
 // Enums
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Harmony {
+    /// Chords are built chromatically from the held modifiers
+    Chromatic,
+    /// Without a held quality, notes play the diatonic chord of the current key
+    Diatonic(NonScaleRule),
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Perform {
     None,
@@ -100,12 +351,29 @@ pub enum Perform {
     Arpeggio2Octave,
 }
 
+impl Perform {
+    pub const ALL: [Perform; 5] = [
+        Perform::None,
+        Perform::Strum,
+        Perform::Strum2Octave,
+        Perform::Arpeggio,
+        Perform::Arpeggio2Octave,
+    ];
+
+    pub fn is_arpeggio(&self) -> bool {
+        matches!(self, Perform::Arpeggio | Perform::Arpeggio2Octave)
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum PerformParam {
     None,
     StrumSpacing(u8),
+    StrumDirection(StrumDirection),
+    VelocityFalloff(u8),
     ArpeggioDirection(ArpeggioDirection),
     ArpeggioRate(Rate),
+    ArpeggioGate(u8),
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -118,21 +386,80 @@ pub enum Rate {
     ThirtySecond = 32,
 }
 
+impl Rate {
+    pub const ALL: [Rate; 6] = [
+        Rate::Quarter,
+        Rate::Eighth,
+        Rate::Twelfth,
+        Rate::Sixteenth,
+        Rate::TwentyFourth,
+        Rate::ThirtySecond,
+    ];
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ArpeggioDirection {
     Up,
     Down,
     UpDown,
+    Random,
+    AsPlayed,
+}
+
+impl ArpeggioDirection {
+    pub const ALL: [ArpeggioDirection; 5] = [
+        ArpeggioDirection::Up,
+        ArpeggioDirection::Down,
+        ArpeggioDirection::UpDown,
+        ArpeggioDirection::Random,
+        ArpeggioDirection::AsPlayed,
+    ];
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StrumDirection {
+    Up,
+    Down,
+    Alternate,
+}
+
+impl StrumDirection {
+    pub const ALL: [StrumDirection; 3] = [
+        StrumDirection::Up,
+        StrumDirection::Down,
+        StrumDirection::Alternate,
+    ];
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Page {
     One,
     Two,
+    Three,
 }
 
 impl Page {
-    fn get_controls(&self) -> [RotaryControl; 4] {
+    pub const ALL: [Page; 3] = [Page::One, Page::Two, Page::Three];
+
+    pub fn next(&self) -> Page {
+        match self {
+            Page::One => Page::Two,
+            Page::Two => Page::Three,
+            Page::Three => Page::One,
+        }
+    }
+
+    /// Find the page and encoder index of a control, ignoring the value it holds
+    pub fn find_control(control: RotaryControl) -> Option<(Page, usize)> {
+        Page::ALL.into_iter().find_map(|page| {
+            page.get_controls()
+                .iter()
+                .position(|c| c.is_same_control(&control))
+                .map(|index| (page, index))
+        })
+    }
+
+    pub fn get_controls(&self) -> [RotaryControl; 4] {
         match self {
             Page::One => [
                 RotaryControl::Root(Note::C4),
@@ -144,7 +471,13 @@ impl Page {
                 RotaryControl::PerformParam(PerformParam::StrumSpacing(20)),
                 RotaryControl::PerformParam(PerformParam::ArpeggioDirection(ArpeggioDirection::Up)),
                 RotaryControl::PerformParam(PerformParam::ArpeggioRate(Rate::Eighth)),
-                RotaryControl::PerformParam(PerformParam::None), // You might want to change this last one
+                RotaryControl::PerformParam(PerformParam::ArpeggioGate(50)),
+            ],
+            Page::Three => [
+                RotaryControl::Voicing(Voicing::Close),
+                RotaryControl::PerformParam(PerformParam::StrumDirection(StrumDirection::Up)),
+                RotaryControl::PerformParam(PerformParam::VelocityFalloff(0)),
+                RotaryControl::PerformParam(PerformParam::None),
             ],
         }
     }
@@ -0,0 +1,289 @@
+use crate::chord_analysis;
+use crate::chord_name::get_chord_name;
+use crate::mapping::Mappings;
+use crate::midi_in::ChordStatus;
+use crate::modifier::{Extension, Inversion, Modifier, ModifierGroup, RangePolicy};
+use crate::state::{
+    ArpeggioDirection, BassVoice, GlobalState, Harmony, LearnTarget, Page, Perform, PerformParam,
+    Rate, RotaryControl,
+};
+use crate::theory::{NonScaleRule, Quantize, Scale};
+use crate::voice_leading::VoiceLeading;
+use crate::voicing::Voicing;
+use serde::Deserialize;
+use serde::de::IntoDeserializer;
+use serde::de::value::{Error as ValueError, StrDeserializer};
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::sync::RwLock;
+use tokio::task::JoinHandle;
+use wmidi::{Channel, Note};
+
+const HELP: &str = "Commands:
+  learn quality <major|minor|diminished|augmented|sus2|sus4|user-defined quality>
+  learn extension <sixth|minor_seventh|major_seventh|ninth|add9|add11|six_nine|...>
+  learn inversion <root|first|second|third>
+  learn control <root|scale|bpm|perform|strum_spacing|arpeggio_direction|arpeggio_rate|arpeggio_gate|voicing>
+  learn page
+  cancel
+  lead <on|off>
+  lead <low note> <high note>
+  bass off
+  bass <channel> [octaves] [omit]
+  range <drop|fold>
+  range clamp <low note> <high note>
+  analyze <note> <note> ...
+  latch <quality|extension|inversion|all|arpeggio> <on|off>
+  clear
+  quantize <off|nearest|up|down|drop>
+  palette [tones]
+  harmony <chromatic|snap|borrowed>";
+
+/// Read commands from the terminal
+pub fn run(
+    state: Arc<RwLock<GlobalState>>,
+    mappings: Arc<RwLock<Mappings>>,
+    status: Arc<RwLock<ChordStatus>>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut lines = BufReader::new(tokio::io::stdin()).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match words.as_slice() {
+                [] => (),
+                ["learn", target @ ..] => match parse_learn_target(target, &*mappings.read().await)
+                {
+                    Some(target) => {
+                        println!("Learning {:?}; press a button on the controller", target);
+                        state.write().await.learn = Some(target);
+                    }
+                    None => println!("Unknown learn target: {}\n{}", target.join(" "), HELP),
+                },
+                ["cancel"] => {
+                    state.write().await.learn = None;
+                    println!("Learn cancelled");
+                }
+                ["lead", args @ ..] => match parse_voice_leading(args) {
+                    Some(voice_leading) => {
+                        println!("Voice leading: {:?}", voice_leading);
+                        state.write().await.voice_leading = voice_leading;
+                    }
+                    None => println!(
+                        "Unknown voice leading setting: {}\n{}",
+                        args.join(" "),
+                        HELP
+                    ),
+                },
+                ["bass", args @ ..] => match parse_bass(args) {
+                    Some(bass) => {
+                        println!("Bass: {:?}", bass);
+                        state.write().await.bass = bass;
+                    }
+                    None => println!("Unknown bass setting: {}\n{}", args.join(" "), HELP),
+                },
+                ["range", args @ ..] => match parse_range(args) {
+                    Some(range) => {
+                        println!("Out of range notes: {:?}", range);
+                        state.write().await.range = range;
+                    }
+                    None => println!("Unknown range setting: {}\n{}", args.join(" "), HELP),
+                },
+                ["analyze", notes @ ..] => {
+                    let notes: Option<Vec<Note>> = notes
+                        .iter()
+                        .map(|n| Note::try_from(n.parse::<u8>().ok()?).ok())
+                        .collect();
+                    match notes {
+                        Some(notes) if !notes.is_empty() => {
+                            for candidate in chord_analysis::analyze(&notes) {
+                                println!("{} (score {})", candidate.symbol, candidate.score);
+                            }
+                        }
+                        _ => println!("Notes must be MIDI note numbers\n{}", HELP),
+                    }
+                }
+                ["latch", "arpeggio", setting] => match *setting {
+                    "on" | "off" => {
+                        // read before locking the state, as the chord engine locks status first
+                        let released_all = status.read().await.is_empty();
+                        let mut state = state.write().await;
+                        state.perform_params.arpeggiator.latch = *setting == "on";
+                        // stop a latched chord that is no longer held
+                        if *setting == "off" && released_all {
+                            state.active_notes.clear();
+                        }
+                        println!("Latch arpeggio {}", setting);
+                    }
+                    _ => println!("Unknown latch setting: arpeggio {}\n{}", setting, HELP),
+                },
+                ["latch", group, setting] => {
+                    let groups: &[ModifierGroup] = match *group {
+                        "quality" => &[ModifierGroup::Quality],
+                        "extension" => &[ModifierGroup::Extension],
+                        "inversion" => &[ModifierGroup::Inversion],
+                        "all" => &ModifierGroup::ALL,
+                        _ => &[],
+                    };
+                    let latched = match *setting {
+                        "on" => Some(true),
+                        "off" => Some(false),
+                        _ => None,
+                    };
+                    match (groups, latched) {
+                        ([_, ..], Some(latched)) => {
+                            let mut state = state.write().await;
+                            for &group in groups {
+                                state.modifier_state.set_latched(group, latched);
+                            }
+                            println!("Latch {} {}", group, setting);
+                        }
+                        _ => println!("Unknown latch setting: {} {}\n{}", group, setting, HELP),
+                    }
+                }
+                ["quantize", setting] => {
+                    let quantize = match *setting {
+                        "off" => Some(None),
+                        "nearest" => Some(Some(Quantize::Nearest)),
+                        "up" => Some(Some(Quantize::Up)),
+                        "down" => Some(Some(Quantize::Down)),
+                        "drop" => Some(Some(Quantize::Drop)),
+                        _ => None,
+                    };
+                    match quantize {
+                        Some(quantize) => {
+                            println!("Quantize: {:?}", quantize);
+                            state.write().await.quantize = quantize;
+                        }
+                        None => println!("Unknown quantize setting: {}\n{}", setting, HELP),
+                    }
+                }
+                ["palette", args @ ..] => {
+                    let tones = match args {
+                        [] => Some(3),
+                        [tones] => tones.parse::<usize>().ok().filter(|t| (1..=7).contains(t)),
+                        _ => None,
+                    };
+                    match tones {
+                        Some(tones) => {
+                            let key = state.read().await.key.clone();
+                            for chord in key.get_diatonic_chords(tones) {
+                                let symbol = get_chord_name(chord.notes[0], &chord.notes);
+                                println!("{:<8} {}", chord.numeral, symbol);
+                            }
+                        }
+                        None => println!("Tones must be from 1 to 7\n{}", HELP),
+                    }
+                }
+                ["harmony", setting] => {
+                    let harmony = match *setting {
+                        "chromatic" => Some(Harmony::Chromatic),
+                        "snap" => Some(Harmony::Diatonic(NonScaleRule::Snap)),
+                        "borrowed" => Some(Harmony::Diatonic(NonScaleRule::Borrowed)),
+                        _ => None,
+                    };
+                    match harmony {
+                        Some(harmony) => {
+                            println!("Harmony: {:?}", harmony);
+                            state.write().await.harmony = harmony;
+                        }
+                        None => println!("Unknown harmony setting: {}\n{}", setting, HELP),
+                    }
+                }
+                ["clear"] => {
+                    state.write().await.modifier_state.clear();
+                    println!("Modifiers cleared");
+                }
+                _ => println!("{}", HELP),
+            }
+        }
+    })
+}
+
+fn parse_learn_target(words: &[&str], mappings: &Mappings) -> Option<LearnTarget> {
+    match words {
+        ["quality", name] => mappings
+            .get_quality(name)
+            .map(|q| LearnTarget::Modifier(Modifier::Quality(q))),
+        ["extension", name] => {
+            parse::<Extension>(name).map(|e| LearnTarget::Modifier(Modifier::Extension(e)))
+        }
+        ["inversion", name] => {
+            parse::<Inversion>(name).map(|i| LearnTarget::Modifier(Modifier::Inversion(i)))
+        }
+        ["control", name] => {
+            let control = match *name {
+                "root" => RotaryControl::Root(Note::C4),
+                "scale" => RotaryControl::Scale(Scale::Ionian),
+                "bpm" => RotaryControl::Bpm(120),
+                "perform" => RotaryControl::Perform(Perform::None),
+                "strum_spacing" => RotaryControl::PerformParam(PerformParam::StrumSpacing(0)),
+                "arpeggio_direction" => RotaryControl::PerformParam(
+                    PerformParam::ArpeggioDirection(ArpeggioDirection::Up),
+                ),
+                "arpeggio_rate" => {
+                    RotaryControl::PerformParam(PerformParam::ArpeggioRate(Rate::Eighth))
+                }
+                "arpeggio_gate" => RotaryControl::PerformParam(PerformParam::ArpeggioGate(0)),
+                "voicing" => RotaryControl::Voicing(Voicing::Close),
+                _ => return None,
+            };
+            Page::find_control(control).map(|_| LearnTarget::Control(control))
+        }
+        ["page"] => Some(LearnTarget::PageButton),
+        _ => None,
+    }
+}
+
+/// Parse a voice leading setting. The register is given as MIDI note numbers.
+fn parse_voice_leading(words: &[&str]) -> Option<Option<VoiceLeading>> {
+    match words {
+        ["on"] => Some(Some(VoiceLeading::new())),
+        ["off"] => Some(None),
+        [low, high] => {
+            let low = Note::try_from(low.parse::<u8>().ok()?).ok()?;
+            let high = Note::try_from(high.parse::<u8>().ok()?).ok()?;
+            // narrower registers leave no room for most chords
+            (u8::from(high) >= u8::from(low) + 11).then_some(Some(VoiceLeading { low, high }))
+        }
+        _ => None,
+    }
+}
+
+/// Parse how notes beyond the MIDI range are handled. The register is given as MIDI note numbers.
+fn parse_range(words: &[&str]) -> Option<RangePolicy> {
+    match words {
+        ["drop"] => Some(RangePolicy::Drop),
+        ["fold"] => Some(RangePolicy::Fold),
+        ["clamp", low, high] => {
+            let low = Note::try_from(low.parse::<u8>().ok()?).ok()?;
+            let high = Note::try_from(high.parse::<u8>().ok()?).ok()?;
+            (low <= high).then_some(RangePolicy::Clamp { low, high })
+        }
+        _ => None,
+    }
+}
+
+/// Parse a bass voice setting: a channel from 1 to 16, optionally followed by the number of
+/// octaves below the chord and `omit` to leave the chord root out of the chord
+fn parse_bass(words: &[&str]) -> Option<Option<BassVoice>> {
+    let (channel, options) = match words {
+        ["off"] => return Some(None),
+        [channel, options @ ..] => (channel.parse::<u8>().ok()?, options),
+        _ => return None,
+    };
+    let mut bass = BassVoice::new(Channel::from_index(channel.checked_sub(1)?).ok()?);
+    for option in options {
+        match *option {
+            "1" | "2" => bass.octaves = option.parse().ok()?,
+            "omit" => bass.omit_from_chord = true,
+            _ => return None,
+        }
+    }
+    Some(Some(bass))
+}
+
+/// Parse a modifier name as it is written in the mapping file
+fn parse<'de, T: Deserialize<'de>>(name: &'de str) -> Option<T> {
+    let deserializer: StrDeserializer<ValueError> = name.into_deserializer();
+    T::deserialize(deserializer).ok()
+}
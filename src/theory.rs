@@ -1,4 +1,7 @@
+use crate::modifier::Quality;
 use cached::proc_macro::cached;
+use std::error::Error;
+use std::fmt;
 use wmidi::{Note, U7};
 
 #[derive(Debug, Clone)]
@@ -25,35 +28,352 @@ impl Key {
         self.scale.get_notes(self.root)
     }
 
-    pub fn get_triad(&self, root: Note) -> Vec<Note> {
-        // get index of root in scale, or next closest above note
-        let index = self.get_notes().iter().position(|n| n >= &root).unwrap();
-        // get notes offset 2 and 4 from index
-        let mut notes = vec![root];
-
-        let scale_notes = &self.get_notes();
-        if let Some(third) = scale_notes.get(index + 2) {
-            notes.push(*third);
+    /// Move a note into the key, or None if it is outside the key and `quantize` drops it
+    pub fn quantize(&self, note: Note, quantize: Quantize) -> Option<Note> {
+        let scale_notes = self.get_notes();
+        if scale_notes.contains(&note) {
+            return Some(note);
         }
-        if let Some(fifth) = scale_notes.get(index + 4) {
-            notes.push(*fifth);
+        let below = scale_notes.iter().rev().find(|n| **n < note).copied();
+        let above = scale_notes.iter().find(|n| **n > note).copied();
+        match quantize {
+            Quantize::Nearest => match (below, above) {
+                // ties go down, as when snapping chords
+                (Some(below), Some(above)) => {
+                    if u8::from(note) - u8::from(below) <= u8::from(above) - u8::from(note) {
+                        Some(below)
+                    } else {
+                        Some(above)
+                    }
+                }
+                (below, above) => below.or(above),
+            },
+            Quantize::Up => above.or(below),
+            Quantize::Down => below.or(above),
+            Quantize::Drop => None,
         }
-        notes
     }
 
-    pub fn get_interval(&self, root: Note, interval: u8) -> Option<Note> {
+    /// Build the diatonic chord on `root` by stacking `tones` scale notes, in thirds or fourths
+    /// depending on the scale, stopping early if the stack would repeat a pitch class. Notes
+    /// outside the key are handled according to `rule`.
+    pub fn get_diatonic_chord(&self, root: Note, tones: usize, rule: NonScaleRule) -> Vec<Note> {
+        let scale_notes = self.get_notes();
+        let index = match scale_notes.iter().position(|n| n == &root) {
+            Some(index) => index,
+            None => match rule {
+                NonScaleRule::Snap => {
+                    // min_by_key keeps the first minimum, so ties snap down to the lower degree
+                    let distance =
+                        |n: &Note| (i16::from(u8::from(*n)) - i16::from(u8::from(root))).abs();
+                    scale_notes
+                        .iter()
+                        .enumerate()
+                        .min_by_key(|(_, n)| distance(n))
+                        .map_or(0, |(index, _)| index)
+                }
+                NonScaleRule::Borrowed => {
+                    // borrowed chords (bIII, bVI, bVII...) are played as major or dominant chords
+                    return [0, 4, 7, 10]
+                        .iter()
+                        .take(tones)
+                        .filter_map(|&semitones| root.step(semitones).ok())
+                        .collect();
+                }
+            },
+        };
+        let chord: Vec<Note> = match self.scale.get_stacking() {
+            Stacking::Thirds => (0..tones)
+                .map_while(|tone| {
+                    let degrees = u8::try_from(tone * 2).ok()?;
+                    self.get_interval(scale_notes[index], degrees).ok()
+                })
+                .collect(),
+            Stacking::Fourths => {
+                let mut chord = vec![scale_notes[index]];
+                let mut index = index;
+                while chord.len() < tones {
+                    // the next tone is a minor third to a fifth above the last
+                    let last = i16::from(u8::from(scale_notes[index]));
+                    let next = scale_notes
+                        .iter()
+                        .enumerate()
+                        .skip(index + 1)
+                        .map(|(i, n)| (i, i16::from(u8::from(*n)) - last))
+                        .take_while(|(_, interval)| *interval <= 7)
+                        .filter(|(_, interval)| *interval >= 3)
+                        .min_by_key(|(_, interval)| (interval - 5).abs());
+                    match next {
+                        Some((next, _)) => {
+                            index = next;
+                            chord.push(scale_notes[index]);
+                        }
+                        None => break,
+                    }
+                }
+                chord
+            }
+        };
+        // scales with few notes come back to a tone's pitch class within the stack, which
+        // would only double it
+        let mut pitch_classes = vec![];
+        chord
+            .into_iter()
+            .take_while(|note| {
+                let pitch_class = u8::from(*note) % 12;
+                let repeated = pitch_classes.contains(&pitch_class);
+                pitch_classes.push(pitch_class);
+                !repeated
+            })
+            .collect()
+    }
+
+    /// Get the chord of `tones` stacked scale notes on `degree`, counting the tonic nearest
+    /// middle C as degree 0, or None if the degree is beyond the MIDI range
+    pub fn get_degree_chord(&self, degree: usize, tones: usize) -> Option<DiatonicChord> {
+        let scale_notes = self.get_notes();
+        let tonic = Note::from_u8_lossy(u8::from(self.root) + 60);
+        let index = scale_notes.iter().position(|n| n == &tonic)? + degree;
+        let root = *scale_notes.get(index)?;
+        let notes = self.get_diatonic_chord(root, tones, NonScaleRule::Snap);
+        let quality = match self.scale.get_stacking() {
+            Stacking::Thirds => get_triad_quality(&notes),
+            Stacking::Fourths => None,
+        };
+        let numeral = get_numeral(u8::from(root) - u8::from(tonic), quality, &notes);
+        Some(DiatonicChord {
+            degree,
+            notes,
+            quality,
+            numeral,
+        })
+    }
+
+    /// Get the chord on every degree of the key, from the tonic up: the palette of the key
+    pub fn get_diatonic_chords(&self, tones: usize) -> Vec<DiatonicChord> {
+        (0..self.scale.get_intervals().len())
+            .filter_map(|degree| self.get_degree_chord(degree, tones))
+            .collect()
+    }
+
+    /// Get the scale note `degrees` scale degrees above the scale note at or above `root`,
+    /// continuing into higher octaves
+    pub fn get_interval(&self, root: Note, degrees: u8) -> Result<Note, KeyError> {
         let notes = self.get_notes();
-        let index = notes.iter().position(|n| n >= &root).unwrap();
-        if let Some(interval_note) = notes.get((index + interval as usize) % notes.len()) {
-            Some(*interval_note)
-        } else {
-            None
+        let index = notes
+            .iter()
+            .position(|n| n >= &root)
+            .ok_or(KeyError::NoScaleNoteAbove(root))?;
+        notes
+            .get(index + usize::from(degrees))
+            .copied()
+            .ok_or(KeyError::BeyondRange { root, degrees })
+    }
+}
+
+/// Why a note couldn't be found in a key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyError {
+    /// There is no scale note at or above the note within the MIDI range
+    NoScaleNoteAbove(Note),
+    /// The interval would go above the highest scale note in the MIDI range
+    BeyondRange { root: Note, degrees: u8 },
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeyError::NoScaleNoteAbove(root) => {
+                write!(f, "no scale note at or above {}", u8::from(*root))
+            }
+            KeyError::BeyondRange { root, degrees } => write!(
+                f,
+                "{} scale degrees above {} is beyond the MIDI range",
+                degrees,
+                u8::from(*root)
+            ),
+        }
+    }
+}
+
+impl Error for KeyError {}
+
+/// A chord built on a degree of a key, with its function in the key
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiatonicChord {
+    /// The scale degree the chord is built on, counting the tonic as 0
+    pub degree: usize,
+    pub notes: Vec<Note>,
+    /// The quality of the triad, or None if the tones don't form one
+    pub quality: Option<Quality>,
+    /// The Roman numeral, e.g. "ii7", "bVII" or "viiø7"
+    pub numeral: String,
+}
+
+/// Roman numerals of the degrees of the major scale, which other scales are written against
+const NUMERALS: [(u8, &str); 7] = [
+    (0, "I"),
+    (2, "II"),
+    (4, "III"),
+    (5, "IV"),
+    (7, "V"),
+    (9, "VI"),
+    (11, "VII"),
+];
+
+/// Read the quality of the triad at the bottom of a chord stacked in thirds
+fn get_triad_quality(notes: &[Note]) -> Option<Quality> {
+    let interval = |i: usize| Some(u8::from(*notes.get(i)?) - u8::from(notes[0]));
+    match (interval(1)?, interval(2)?) {
+        (3, 6) => Some(Quality::Diminished),
+        (3, 7) => Some(Quality::Minor),
+        (4, 7) => Some(Quality::Major),
+        (4, 8) => Some(Quality::Augmented),
+        (2, 7) => Some(Quality::Sus2),
+        (5, 7) => Some(Quality::Sus4),
+        _ => None,
+    }
+}
+
+/// Write the Roman numeral of a chord `semitones` above the tonic. Degrees missing from the major
+/// scale are written as a flattened degree, e.g. "bIII"; minor and diminished chords are written
+/// in lower case, and the top of the stack as a figure, e.g. "V9" or "IVmaj7".
+fn get_numeral(semitones: u8, quality: Option<Quality>, notes: &[Note]) -> String {
+    let mut numeral = String::new();
+    let (_, degree) = match NUMERALS.iter().find(|(s, _)| *s == semitones % 12) {
+        Some(degree) => *degree,
+        None => {
+            numeral.push('b');
+            *NUMERALS
+                .iter()
+                .find(|(s, _)| *s == semitones % 12 + 1)
+                .unwrap()
+        }
+    };
+    match quality {
+        Some(Quality::Minor | Quality::Diminished) => numeral.push_str(&degree.to_lowercase()),
+        _ => numeral.push_str(degree),
+    }
+
+    let seventh = notes.get(3).map(|n| u8::from(*n) - u8::from(notes[0]));
+    let figure = match notes.len() {
+        // chords stacked in fourths aren't read as sevenths
+        _ if quality.is_none() => "",
+        0..=3 => "",
+        4 => "7",
+        5 => "9",
+        6 => "11",
+        _ => "13",
+    };
+    match (quality, seventh) {
+        (Some(Quality::Diminished), Some(9)) => numeral.push('°'),
+        (Some(Quality::Diminished), Some(10)) => numeral.push('ø'),
+        (Some(Quality::Diminished), _) => numeral.push('°'),
+        (Some(Quality::Augmented), _) => numeral.push('+'),
+        _ => (),
+    }
+    match (quality, seventh) {
+        // kept apart from the lower case numeral, as chord symbols write "m(maj7)"
+        (Some(Quality::Minor), Some(11)) => {
+            numeral.push_str("(maj");
+            numeral.push_str(figure);
+            numeral.push(')');
+        }
+        (_, Some(11)) => {
+            numeral.push_str("maj");
+            numeral.push_str(figure);
         }
+        _ => numeral.push_str(figure),
     }
+    match quality {
+        Some(Quality::Sus2) => numeral.push_str("sus2"),
+        Some(Quality::Sus4) => numeral.push_str("sus4"),
+        _ => (),
+    }
+    numeral
+}
+
+/// How played notes outside the key are moved into it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quantize {
+    Nearest,
+    Up,
+    Down,
+    /// Don't play notes outside the key
+    Drop,
 }
 
+/// How chord tones are picked from a scale
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stacking {
+    /// Every second scale degree, as in seven-note scales and symmetrical scales
+    Thirds,
+    /// The scale note nearest a perfect fourth above the previous tone, for scales with too few
+    /// or too many notes to stack in thirds
+    Fourths,
+}
+
+/// A user-defined scale, as the set of pitch classes above its root
+#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq)]
+pub struct CustomScale {
+    /// Bit n is set if the note n semitones above the root is in the scale
+    pitch_classes: u16,
+}
+
+impl CustomScale {
+    /// Create a scale from its steps in semitones, or None if they don't add up to an octave
+    pub fn new(steps: &[u8]) -> Option<Self> {
+        if steps.contains(&0) || steps.iter().map(|&s| u16::from(s)).sum::<u16>() != 12 {
+            return None;
+        }
+        let mut offset = 0;
+        let mut pitch_classes = 1;
+        for step in &steps[..steps.len() - 1] {
+            offset += step;
+            pitch_classes |= 1 << offset;
+        }
+        Some(Self { pitch_classes })
+    }
+
+    /// Get the steps in semitones between successive notes of the scale, ending on the octave
+    pub fn get_intervals(&self) -> Vec<u8> {
+        let offsets: Vec<u8> = (0..12)
+            .filter(|offset| self.pitch_classes & (1 << offset) != 0)
+            .chain([12])
+            .collect();
+        offsets.windows(2).map(|pair| pair[1] - pair[0]).collect()
+    }
+}
+
+/// How notes outside the key are harmonized when playing diatonic chords
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NonScaleRule {
+    /// Snap the note to the nearest scale degree and play that degree's chord
+    Snap,
+    /// Play a chromatic major chord on the note, as if borrowed from a parallel key
+    Borrowed,
+}
+
+/// Steps of the seven-note scales that the modes are rotations of
+const MAJOR: [u8; 7] = [2, 2, 1, 2, 2, 2, 1];
+const HARMONIC_MINOR: [u8; 7] = [2, 1, 2, 2, 1, 3, 1];
+const MELODIC_MINOR: [u8; 7] = [2, 1, 2, 2, 2, 2, 1];
+const HARMONIC_MAJOR: [u8; 7] = [2, 2, 1, 2, 1, 3, 1];
+
+/// Steps of the scales with more or fewer than seven notes
+const MAJOR_PENTATONIC: [u8; 5] = [2, 2, 3, 2, 3];
+const MINOR_PENTATONIC: [u8; 5] = [3, 2, 2, 3, 2];
+const BLUES: [u8; 6] = [3, 2, 1, 1, 3, 2];
+const WHOLE_TONE: [u8; 6] = [2, 2, 2, 2, 2, 2];
+const HALF_WHOLE: [u8; 8] = [1, 2, 1, 2, 1, 2, 1, 2];
+const WHOLE_HALF: [u8; 8] = [2, 1, 2, 1, 2, 1, 2, 1];
+const BEBOP_DOMINANT: [u8; 8] = [2, 2, 1, 2, 2, 1, 1, 1];
+const BEBOP_MAJOR: [u8; 8] = [2, 2, 1, 2, 1, 1, 2, 1];
+const CHROMATIC: [u8; 12] = [1; 12];
+
 #[derive(Debug, Clone, Copy, Hash, Eq, PartialEq)]
 pub enum Scale {
+    // modes of major
     Ionian,
     Dorian,
     Phrygian,
@@ -61,6 +381,7 @@ pub enum Scale {
     Mixolydian,
     Aeolian,
     Locrian,
+    // modes of harmonic minor
     HarmonicMinor,
     LocrianNatural6,
     IonianSharp5,
@@ -68,59 +389,168 @@ pub enum Scale {
     PhrygianDominant,
     LydianSharp9,
     AlteredDiminished,
-    HarmonicMajor,
+    // modes of melodic minor
     MelodicMinor,
+    DorianFlat2,
+    LydianAugmented,
+    LydianDominant,
+    MixolydianFlat6,
+    LocrianNatural2,
+    Altered,
+    // modes of harmonic major
+    HarmonicMajor,
+    DorianFlat5,
+    PhrygianFlat4,
+    LydianFlat3,
+    MixolydianFlat2,
+    LydianAugmentedSharp2,
+    LocrianDoubleFlat7,
+    // scales with more or fewer than seven notes
+    MajorPentatonic,
+    MinorPentatonic,
+    Blues,
+    WholeTone,
+    HalfWholeDiminished,
+    WholeHalfDiminished,
+    BebopDominant,
+    BebopMajor,
+    Chromatic,
+    /// A scale defined in the mapping file
+    Custom(CustomScale),
 }
 
 impl Scale {
     pub const Major: Scale = Scale::Ionian;
     pub const Minor: Scale = Scale::Aeolian;
 
-    pub fn get_intervals(&self) -> Vec<u8> {
+    /// Every built-in scale
+    pub const ALL: [Scale; 37] = [
+        Scale::Ionian,
+        Scale::Dorian,
+        Scale::Phrygian,
+        Scale::Lydian,
+        Scale::Mixolydian,
+        Scale::Aeolian,
+        Scale::Locrian,
+        Scale::HarmonicMinor,
+        Scale::LocrianNatural6,
+        Scale::IonianSharp5,
+        Scale::DorianSharp4,
+        Scale::PhrygianDominant,
+        Scale::LydianSharp9,
+        Scale::AlteredDiminished,
+        Scale::MelodicMinor,
+        Scale::DorianFlat2,
+        Scale::LydianAugmented,
+        Scale::LydianDominant,
+        Scale::MixolydianFlat6,
+        Scale::LocrianNatural2,
+        Scale::Altered,
+        Scale::HarmonicMajor,
+        Scale::DorianFlat5,
+        Scale::PhrygianFlat4,
+        Scale::LydianFlat3,
+        Scale::MixolydianFlat2,
+        Scale::LydianAugmentedSharp2,
+        Scale::LocrianDoubleFlat7,
+        Scale::MajorPentatonic,
+        Scale::MinorPentatonic,
+        Scale::Blues,
+        Scale::WholeTone,
+        Scale::HalfWholeDiminished,
+        Scale::WholeHalfDiminished,
+        Scale::BebopDominant,
+        Scale::BebopMajor,
+        Scale::Chromatic,
+    ];
+
+    /// Get the parent scale and the degree of it the mode starts on, counting from 0. Custom
+    /// scales have no parent.
+    fn get_mode(&self) -> Option<(&'static [u8], usize)> {
+        use Scale::*;
         match self {
-            Scale::Ionian => vec![2, 2, 1, 2, 2, 2, 1],
-            Scale::Dorian => Scale::get_mode_intervals(&Scale::Ionian.get_intervals(), 1),
-            Scale::Phrygian => Scale::get_mode_intervals(&Scale::Ionian.get_intervals(), 2),
-            Scale::Lydian => Scale::get_mode_intervals(&Scale::Ionian.get_intervals(), 3),
-            Scale::Mixolydian => Scale::get_mode_intervals(&Scale::Ionian.get_intervals(), 4),
-            Scale::Aeolian => Scale::get_mode_intervals(&Scale::Ionian.get_intervals(), 5),
-            Scale::Locrian => Scale::get_mode_intervals(&Scale::Ionian.get_intervals(), 6),
-            Scale::HarmonicMinor => vec![2, 1, 2, 2, 1, 3, 1],
-            Scale::LocrianNatural6 => {
-                Scale::get_mode_intervals(&Scale::HarmonicMinor.get_intervals(), 1)
-            }
-            Scale::IonianSharp5 => Scale::get_mode_intervals(&Scale::Ionian.get_intervals(), 2),
-            Scale::DorianSharp4 => Scale::get_mode_intervals(&Scale::Ionian.get_intervals(), 3),
-            Scale::PhrygianDominant => Scale::get_mode_intervals(&Scale::Ionian.get_intervals(), 4),
-            Scale::LydianSharp9 => Scale::get_mode_intervals(&Scale::Ionian.get_intervals(), 5),
-            Scale::AlteredDiminished => {
-                Scale::get_mode_intervals(&Scale::Ionian.get_intervals(), 6)
+            Ionian => Some((&MAJOR, 0)),
+            Dorian => Some((&MAJOR, 1)),
+            Phrygian => Some((&MAJOR, 2)),
+            Lydian => Some((&MAJOR, 3)),
+            Mixolydian => Some((&MAJOR, 4)),
+            Aeolian => Some((&MAJOR, 5)),
+            Locrian => Some((&MAJOR, 6)),
+            HarmonicMinor => Some((&HARMONIC_MINOR, 0)),
+            LocrianNatural6 => Some((&HARMONIC_MINOR, 1)),
+            IonianSharp5 => Some((&HARMONIC_MINOR, 2)),
+            DorianSharp4 => Some((&HARMONIC_MINOR, 3)),
+            PhrygianDominant => Some((&HARMONIC_MINOR, 4)),
+            LydianSharp9 => Some((&HARMONIC_MINOR, 5)),
+            AlteredDiminished => Some((&HARMONIC_MINOR, 6)),
+            MelodicMinor => Some((&MELODIC_MINOR, 0)),
+            DorianFlat2 => Some((&MELODIC_MINOR, 1)),
+            LydianAugmented => Some((&MELODIC_MINOR, 2)),
+            LydianDominant => Some((&MELODIC_MINOR, 3)),
+            MixolydianFlat6 => Some((&MELODIC_MINOR, 4)),
+            LocrianNatural2 => Some((&MELODIC_MINOR, 5)),
+            Altered => Some((&MELODIC_MINOR, 6)),
+            HarmonicMajor => Some((&HARMONIC_MAJOR, 0)),
+            DorianFlat5 => Some((&HARMONIC_MAJOR, 1)),
+            PhrygianFlat4 => Some((&HARMONIC_MAJOR, 2)),
+            LydianFlat3 => Some((&HARMONIC_MAJOR, 3)),
+            MixolydianFlat2 => Some((&HARMONIC_MAJOR, 4)),
+            LydianAugmentedSharp2 => Some((&HARMONIC_MAJOR, 5)),
+            LocrianDoubleFlat7 => Some((&HARMONIC_MAJOR, 6)),
+            MajorPentatonic => Some((&MAJOR_PENTATONIC, 0)),
+            MinorPentatonic => Some((&MINOR_PENTATONIC, 0)),
+            Blues => Some((&BLUES, 0)),
+            WholeTone => Some((&WHOLE_TONE, 0)),
+            HalfWholeDiminished => Some((&HALF_WHOLE, 0)),
+            WholeHalfDiminished => Some((&WHOLE_HALF, 0)),
+            BebopDominant => Some((&BEBOP_DOMINANT, 0)),
+            BebopMajor => Some((&BEBOP_MAJOR, 0)),
+            Chromatic => Some((&CHROMATIC, 0)),
+            Custom(_) => None,
+        }
+    }
+
+    /// Get the steps in semitones between successive notes of the scale, ending on the octave
+    pub fn get_intervals(&self) -> Vec<u8> {
+        match (self, self.get_mode()) {
+            (Scale::Custom(custom), _) => custom.get_intervals(),
+            (_, Some((parent, degree))) => {
+                let mut intervals = parent.to_vec();
+                intervals.rotate_left(degree);
+                intervals
             }
-            Scale::HarmonicMajor => vec![2, 2, 1, 2, 1, 3, 1],
-            Scale::MelodicMinor => vec![2, 1, 2, 2, 2, 2, 1],
+            (_, None) => CHROMATIC.to_vec(),
         }
     }
 
-    fn get_mode_intervals(intervals: &Vec<u8>, offset: usize) -> Vec<u8> {
-        let mut new_intervals = intervals.clone();
-        new_intervals.rotate_left(offset % intervals.len());
-        new_intervals
+    /// Get how chords are stacked from the scale's notes
+    pub fn get_stacking(&self) -> Stacking {
+        match self {
+            Scale::MajorPentatonic | Scale::MinorPentatonic | Scale::Blues | Scale::Chromatic => {
+                Stacking::Fourths
+            }
+            Scale::Custom(custom) if !(7..=8).contains(&custom.get_intervals().len()) => {
+                Stacking::Fourths
+            }
+            _ => Stacking::Thirds,
+        }
     }
 
     /// Get the tonic notes of the scale in octave -2, ordered by their note value
     pub fn get_tonic_notes(&self, root: Note) -> Vec<Note> {
-        let note_value = u8::from(root);
-        let base_note = note_value % 12;
+        let base_note = u8::from(root) % 12;
+        // the last step returns to the octave, so it's left out
         let intervals = self.get_intervals();
-        let mut notes = Vec::new();
-        let base_root = Note::from_u8_lossy(base_note);
-        notes.push(base_root);
-        for interval in intervals {
-            // get the note at the interval, then wrap it around mod 12
-            let transposed_note = notes.last().unwrap().step(interval as i8).unwrap();
-            let normalized_note = Note::from_u8_lossy((u8::from(transposed_note) % 12));
-            notes.push(normalized_note);
-        }
+        let mut offset = 0;
+        let mut notes: Vec<Note> = intervals[..intervals.len() - 1]
+            .iter()
+            .map(|interval| {
+                offset += interval;
+                offset
+            })
+            .chain([0])
+            .map(|offset| Note::from_u8_lossy((base_note + offset) % 12))
+            .collect();
         // sort the vector; c-2 will be first, B-2 will be last
         notes.sort();
         notes
@@ -128,33 +558,273 @@ impl Scale {
 
     /// Get ALL notes in the scale, from octave -2 to octave 8
     pub fn get_notes(&self, root: Note) -> Vec<Note> {
-        let note_value = u8::from(root);
-        let base_note = note_value % 12;
-        let intervals = self.get_intervals();
-        let mut notes = self.get_tonic_notes(root);
-        // get index of base_note in notes
-        let base_note_index = notes
-            .iter()
-            .position(|n| n == &Note::from_u8_lossy(base_note))
-            .unwrap();
-        // determine last interval index using base_note_index and length of notes
-        // eg 7 - 1 - 0 = 6; 6 % 7 = 6, next step is to the octave
-        // eg 7 - 1 - 1 = 5; 5 % 7 = 5, next step is to the 7th
-        // eg 7 - 1 - 6 = 0; 0 % 7 = 0, next step is to the second
-        let mut current_interval_idx = notes.len() - 1 - base_note_index;
-        let mut current_note = *notes.last().unwrap();
-        while u8::from(current_note) < 127 {
-            let interval = intervals[current_interval_idx % intervals.len()];
-            match current_note.step(interval as i8) {
-                Ok(next_note) => {
-                    current_note = next_note;
-                    notes.push(current_note);
-                    current_interval_idx += 1;
+        let tonic_notes = self.get_tonic_notes(root);
+        (0..=127)
+            .map(Note::from_u8_lossy)
+            .filter(|note| {
+                tonic_notes
+                    .iter()
+                    .any(|tonic| u8::from(*tonic) == u8::from(*note) % 12)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Pitch classes of each scale on C, as the scale is usually spelled
+    fn spelling(scale: Scale) -> &'static [u8] {
+        match scale {
+            Scale::Ionian => &[0, 2, 4, 5, 7, 9, 11],
+            Scale::Dorian => &[0, 2, 3, 5, 7, 9, 10],
+            Scale::Phrygian => &[0, 1, 3, 5, 7, 8, 10],
+            Scale::Lydian => &[0, 2, 4, 6, 7, 9, 11],
+            Scale::Mixolydian => &[0, 2, 4, 5, 7, 9, 10],
+            Scale::Aeolian => &[0, 2, 3, 5, 7, 8, 10],
+            Scale::Locrian => &[0, 1, 3, 5, 6, 8, 10],
+            Scale::HarmonicMinor => &[0, 2, 3, 5, 7, 8, 11],
+            Scale::LocrianNatural6 => &[0, 1, 3, 5, 6, 9, 10],
+            Scale::IonianSharp5 => &[0, 2, 4, 5, 8, 9, 11],
+            Scale::DorianSharp4 => &[0, 2, 3, 6, 7, 9, 10],
+            Scale::PhrygianDominant => &[0, 1, 4, 5, 7, 8, 10],
+            Scale::LydianSharp9 => &[0, 3, 4, 6, 7, 9, 11],
+            Scale::AlteredDiminished => &[0, 1, 3, 4, 6, 8, 9],
+            Scale::MelodicMinor => &[0, 2, 3, 5, 7, 9, 11],
+            Scale::DorianFlat2 => &[0, 1, 3, 5, 7, 9, 10],
+            Scale::LydianAugmented => &[0, 2, 4, 6, 8, 9, 11],
+            Scale::LydianDominant => &[0, 2, 4, 6, 7, 9, 10],
+            Scale::MixolydianFlat6 => &[0, 2, 4, 5, 7, 8, 10],
+            Scale::LocrianNatural2 => &[0, 2, 3, 5, 6, 8, 10],
+            Scale::Altered => &[0, 1, 3, 4, 6, 8, 10],
+            Scale::HarmonicMajor => &[0, 2, 4, 5, 7, 8, 11],
+            Scale::DorianFlat5 => &[0, 2, 3, 5, 6, 9, 10],
+            Scale::PhrygianFlat4 => &[0, 1, 3, 4, 7, 8, 10],
+            Scale::LydianFlat3 => &[0, 2, 3, 6, 7, 9, 11],
+            Scale::MixolydianFlat2 => &[0, 1, 4, 5, 7, 9, 10],
+            Scale::LydianAugmentedSharp2 => &[0, 3, 4, 6, 8, 9, 11],
+            Scale::LocrianDoubleFlat7 => &[0, 1, 3, 5, 6, 8, 9],
+            Scale::MajorPentatonic => &[0, 2, 4, 7, 9],
+            Scale::MinorPentatonic => &[0, 3, 5, 7, 10],
+            Scale::Blues => &[0, 3, 5, 6, 7, 10],
+            Scale::WholeTone => &[0, 2, 4, 6, 8, 10],
+            Scale::HalfWholeDiminished => &[0, 1, 3, 4, 6, 7, 9, 10],
+            Scale::WholeHalfDiminished => &[0, 2, 3, 5, 6, 8, 9, 11],
+            Scale::BebopDominant => &[0, 2, 4, 5, 7, 9, 10, 11],
+            Scale::BebopMajor => &[0, 2, 4, 5, 7, 8, 9, 11],
+            Scale::Chromatic => &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11],
+            Scale::Custom(_) => &[],
+        }
+    }
+
+    fn pitch_classes(notes: &[Note]) -> Vec<u8> {
+        let mut pitch_classes: Vec<u8> = notes.iter().map(|n| u8::from(*n) % 12).collect();
+        pitch_classes.sort();
+        pitch_classes.dedup();
+        pitch_classes
+    }
+
+    #[test]
+    fn every_scale_matches_its_spelling() {
+        for scale in Scale::ALL {
+            assert_eq!(
+                pitch_classes(&scale.get_tonic_notes(Note::C4)),
+                spelling(scale),
+                "{:?}",
+                scale
+            );
+            assert_eq!(
+                scale
+                    .get_intervals()
+                    .iter()
+                    .map(|&s| u32::from(s))
+                    .sum::<u32>(),
+                12,
+                "{:?}",
+                scale
+            );
+        }
+    }
+
+    #[test]
+    fn stacks_never_repeat_a_pitch_class() {
+        for scale in Scale::ALL {
+            let key = Key::new(Note::C4, scale);
+            for root in scale
+                .get_notes(Note::C4)
+                .into_iter()
+                .filter(|n| *n >= Note::C3)
+            {
+                for tones in 1..=7 {
+                    let chord = key.get_diatonic_chord(root, tones, NonScaleRule::Snap);
+                    assert!(!chord.is_empty() && chord.len() <= tones);
+                    assert!(chord.windows(2).all(|pair| pair[0] < pair[1]));
+                    assert_eq!(pitch_classes(&chord).len(), chord.len(), "{:?}", scale);
                 }
-                Err(_) => break,
             }
         }
+    }
 
-        notes
+    #[test]
+    fn stacks_scales_without_seven_notes() {
+        let chord = |scale: Scale, root: Note, tones: usize| -> Vec<u8> {
+            Key::new(Note::C4, scale)
+                .get_diatonic_chord(root, tones, NonScaleRule::Snap)
+                .iter()
+                .map(|n| u8::from(*n))
+                .collect()
+        };
+        // C E G#, without the octave the fourth stacked third would reach
+        assert_eq!(chord(Scale::WholeTone, Note::C4, 4), [60, 64, 68]);
+        // C Eb F# A
+        assert_eq!(
+            chord(Scale::WholeHalfDiminished, Note::C4, 5),
+            [60, 63, 66, 69]
+        );
+        // C E G Bb, stopping before the octave
+        assert_eq!(chord(Scale::BebopDominant, Note::C4, 5), [60, 64, 67, 70]);
+        // C E A D G in fourths, stopping before C
+        assert_eq!(
+            chord(Scale::MajorPentatonic, Note::C4, 6),
+            [60, 64, 69, 74, 79]
+        );
+        // C F Bb
+        assert_eq!(chord(Scale::MinorPentatonic, Note::C4, 3), [60, 65, 70]);
+        // C F Bb Eb in fourths through every semitone
+        assert_eq!(chord(Scale::Chromatic, Note::C4, 4), [60, 65, 70, 75]);
+    }
+
+    #[test]
+    fn intervals_cross_octaves_up_to_the_top_of_the_range() {
+        for scale in Scale::ALL {
+            for key in [Note::C4, Note::FSharp4] {
+                let key = Key::new(key, scale);
+                let scale_notes = key.get_notes();
+                let size = scale.get_intervals().len() as u8;
+                let highest = *scale_notes.last().unwrap();
+                for root in (0..=127).map(Note::from_u8_lossy) {
+                    if root > highest {
+                        assert_eq!(
+                            key.get_interval(root, 0),
+                            Err(KeyError::NoScaleNoteAbove(root))
+                        );
+                        continue;
+                    }
+                    let index = scale_notes.iter().position(|n| *n >= root).unwrap();
+                    for degrees in 0..=size * 2 {
+                        match scale_notes.get(index + usize::from(degrees)) {
+                            Some(&expected) => {
+                                assert_eq!(key.get_interval(root, degrees), Ok(expected));
+                                // a scale's worth of degrees is an octave
+                                if degrees >= size {
+                                    let below = key.get_interval(root, degrees - size).unwrap();
+                                    assert_eq!(u8::from(expected) - u8::from(below), 12);
+                                }
+                            }
+                            None => assert_eq!(
+                                key.get_interval(root, degrees),
+                                Err(KeyError::BeyondRange { root, degrees })
+                            ),
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn finds_intervals_at_the_top_of_the_range() {
+        let key = Key::new(Note::C4, Scale::Ionian);
+        assert_eq!(key.get_interval(Note::B8, 4), Ok(Note::F9));
+        // A# moves up to B
+        assert_eq!(key.get_interval(Note::ASharp8, 0), Ok(Note::B8));
+        assert_eq!(key.get_interval(Note::ASharp8, 2), Ok(Note::D9));
+        assert_eq!(
+            key.get_interval(Note::F9, 2),
+            Err(KeyError::BeyondRange {
+                root: Note::F9,
+                degrees: 2
+            })
+        );
+        assert_eq!(
+            key.get_diatonic_chord(Note::B8, 3, NonScaleRule::Snap),
+            [Note::B8, Note::D9, Note::F9]
+        );
+        // the stack stops where the next third would be beyond the top of the range
+        assert_eq!(
+            key.get_diatonic_chord(Note::F9, 3, NonScaleRule::Snap),
+            [Note::F9]
+        );
+        // G is the highest MIDI note, and isn't in D flat major
+        let key = Key::new(Note::CSharp4, Scale::Ionian);
+        assert_eq!(key.get_interval(Note::FSharp9, 0), Ok(Note::FSharp9));
+        assert_eq!(
+            key.get_interval(Note::G9, 0),
+            Err(KeyError::NoScaleNoteAbove(Note::G9))
+        );
+    }
+
+    fn palette(scale: Scale, tones: usize) -> Vec<String> {
+        Key::new(Note::A4, scale)
+            .get_diatonic_chords(tones)
+            .into_iter()
+            .map(|chord| chord.numeral)
+            .collect()
+    }
+
+    #[test]
+    fn names_the_major_palette() {
+        assert_eq!(
+            palette(Scale::Ionian, 3),
+            ["I", "ii", "iii", "IV", "V", "vi", "vii°"]
+        );
+        assert_eq!(
+            palette(Scale::Ionian, 4),
+            ["Imaj7", "ii7", "iii7", "IVmaj7", "V7", "vi7", "viiø7"]
+        );
+        assert_eq!(palette(Scale::Ionian, 5)[4], "V9");
+    }
+
+    #[test]
+    fn names_the_minor_palettes() {
+        assert_eq!(
+            palette(Scale::Aeolian, 3),
+            ["i", "ii°", "bIII", "iv", "v", "bVI", "bVII"]
+        );
+        assert_eq!(
+            palette(Scale::HarmonicMinor, 4),
+            [
+                "i(maj7)",
+                "iiø7",
+                "bIII+maj7",
+                "iv7",
+                "V7",
+                "bVImaj7",
+                "vii°7"
+            ]
+        );
+        assert_eq!(
+            palette(Scale::MelodicMinor, 4),
+            ["i(maj7)", "ii7", "bIII+maj7", "IV7", "V7", "viø7", "viiø7"]
+        );
+    }
+
+    #[test]
+    fn leaves_the_figure_off_stacks_without_a_seventh() {
+        assert_eq!(
+            palette(Scale::WholeTone, 4),
+            ["I+", "II+", "III+", "bV+", "bVI+", "bVII+"]
+        );
+    }
+
+    #[test]
+    fn scales_transpose_with_the_root() {
+        for scale in Scale::ALL {
+            let mut on_d: Vec<u8> = spelling(scale).iter().map(|pc| (pc + 2) % 12).collect();
+            on_d.sort();
+            assert_eq!(pitch_classes(&scale.get_tonic_notes(Note::D4)), on_d);
+            assert_eq!(pitch_classes(&scale.get_notes(Note::D4)), on_d);
+        }
     }
 }
@@ -0,0 +1,160 @@
+use wmidi::Note;
+
+/// Register the voice-led chords are kept within
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VoiceLeading {
+    pub low: Note,
+    pub high: Note,
+}
+
+impl VoiceLeading {
+    pub fn new() -> Self {
+        Self {
+            low: Note::C3,
+            high: Note::G5,
+        }
+    }
+
+    /// Voice `chord` so that it moves as little as possible from `previous`, trying every
+    /// inversion of the chord at every octave that fits within the register. Without a previous
+    /// chord, the voicing nearest the chord as built is used. If no voicing fits, the chord is
+    /// returned unchanged.
+    pub fn lead(&self, previous: Option<&[Note]>, chord: &[Note]) -> Vec<Note> {
+        let mut notes: Vec<i32> = chord.iter().map(|n| i32::from(u8::from(*n))).collect();
+        notes.sort();
+        notes.dedup();
+        if notes.is_empty() {
+            return chord.to_vec();
+        }
+        let reference: Vec<i32> = match previous {
+            Some(previous) if !previous.is_empty() => {
+                previous.iter().map(|n| i32::from(u8::from(*n))).collect()
+            }
+            _ => notes.clone(),
+        };
+        let low = i32::from(u8::from(self.low));
+        let high = i32::from(u8::from(self.high));
+
+        let mut best: Option<(i32, Vec<i32>)> = None;
+        for inversion in 0..notes.len() {
+            // raise the lowest notes an octave, keeping the spread of the chord
+            let mut voicing: Vec<i32> = notes[inversion..]
+                .iter()
+                .copied()
+                .chain(notes[..inversion].iter().map(|n| n + 12))
+                .collect();
+            voicing.sort();
+            let (bottom, top) = (voicing[0], voicing[voicing.len() - 1]);
+            // every octave shift that puts the whole voicing inside the register
+            let lowest_shift = (low - bottom + 11).div_euclid(12);
+            let highest_shift = (high - top).div_euclid(12);
+            for shift in lowest_shift..=highest_shift {
+                let candidate: Vec<i32> = voicing.iter().map(|n| n + shift * 12).collect();
+                let cost = movement(&reference, &candidate);
+                if best.as_ref().is_none_or(|(best_cost, _)| cost < *best_cost) {
+                    best = Some((cost, candidate));
+                }
+            }
+        }
+
+        match best {
+            Some((_, voicing)) => voicing
+                .into_iter()
+                .map(|n| Note::from_u8_lossy(n as u8))
+                .collect(),
+            None => chord.to_vec(),
+        }
+    }
+}
+
+/// Total distance in semitones between the voices of two chords. Each note is matched to the
+/// nearest note of the other chord, so chords of different sizes can be compared.
+fn movement(from: &[i32], to: &[i32]) -> i32 {
+    let nearest = |note: &i32, chord: &[i32]| {
+        chord
+            .iter()
+            .map(|other| (note - other).abs())
+            .min()
+            .unwrap_or(0)
+    };
+    from.iter().map(|n| nearest(n, to)).sum::<i32>()
+        + to.iter().map(|n| nearest(n, from)).sum::<i32>()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::voicing::Voicing;
+
+    fn notes(values: &[u8]) -> Vec<Note> {
+        values.iter().map(|&v| Note::from_u8_lossy(v)).collect()
+    }
+
+    #[test]
+    fn leads_a_progression_by_step() {
+        let leading = VoiceLeading::new();
+        // C, F and G major in root position
+        let progression = [
+            notes(&[60, 64, 67]),
+            notes(&[65, 69, 72]),
+            notes(&[67, 71, 74]),
+        ];
+        let mut previous: Option<Vec<Note>> = None;
+        for chord in &progression {
+            let led = leading.lead(previous.as_deref(), chord);
+            if let Some(previous) = &previous {
+                // every voice moves by a step at most
+                let step = |from: &[Note], to: &[Note]| {
+                    from.iter().all(|a| {
+                        to.iter()
+                            .any(|b| (i16::from(u8::from(*a)) - i16::from(u8::from(*b))).abs() <= 2)
+                    })
+                };
+                assert!(
+                    step(previous, &led) && step(&led, previous),
+                    "{:?} to {:?}",
+                    previous,
+                    led
+                );
+            }
+            previous = Some(led);
+        }
+        assert_eq!(previous, Some(notes(&[59, 62, 67])));
+    }
+
+    #[test]
+    fn stays_in_the_register() {
+        let leading = VoiceLeading::new();
+        let mut previous = notes(&[60, 64, 67]);
+        for root in 0..=115 {
+            // a major seventh chord on every root
+            let chord = notes(&[root, root + 4, root + 7, root + 11]);
+            let led = leading.lead(Some(&previous), &chord);
+            assert!(
+                led.iter().all(|n| (leading.low..=leading.high).contains(n)),
+                "{:?}",
+                led
+            );
+            let pitch_classes = |chord: &[Note]| {
+                let mut classes: Vec<u8> = chord.iter().map(|n| u8::from(*n) % 12).collect();
+                classes.sort();
+                classes
+            };
+            assert_eq!(pitch_classes(&led), pitch_classes(&chord));
+            previous = led;
+        }
+    }
+
+    #[test]
+    fn keeps_chords_wider_than_the_register() {
+        let leading = VoiceLeading {
+            low: Note::C4,
+            high: Note::B4,
+        };
+        // a dominant ninth spread over more than two octaves
+        let mut chord = notes(&[60, 64, 67, 70, 74]);
+        Voicing::Spread.apply(&mut chord, Note::C4);
+        assert_eq!(leading.lead(Some(&notes(&[60, 64, 67])), &chord), chord);
+        assert_eq!(leading.lead(None, &chord), chord);
+    }
+}
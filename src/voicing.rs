@@ -0,0 +1,238 @@
+use wmidi::Note;
+
+/// How the tones of a chord are spread across octaves
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Voicing {
+    /// Tones stacked as closely as possible
+    Close,
+    /// Every second tone from the bottom raised an octave
+    Open,
+    /// Second highest tone dropped an octave
+    Drop2,
+    /// Third highest tone dropped an octave
+    Drop3,
+    /// Second and fourth highest tones dropped an octave
+    Drop2And4,
+    /// The chord's tones rearranged to stack as nearly as possible in fourths, e.g. E A D G C
+    /// for C6/9
+    Quartal,
+    /// Root, third and seventh only
+    Shell,
+    /// Every tone but the root
+    Rootless,
+    /// Root an octave below an open voicing of the other tones
+    Spread,
+}
+
+impl Voicing {
+    pub const ALL: [Voicing; 9] = [
+        Voicing::Close,
+        Voicing::Open,
+        Voicing::Drop2,
+        Voicing::Drop3,
+        Voicing::Drop2And4,
+        Voicing::Quartal,
+        Voicing::Shell,
+        Voicing::Rootless,
+        Voicing::Spread,
+    ];
+
+    /// Revoice a close chord built on `root`, which may be inverted. Notes that would leave the
+    /// MIDI range are left in place.
+    pub fn apply(&self, notes: &mut Vec<Note>, root: Note) {
+        match self {
+            Voicing::Close => (),
+            Voicing::Open => open(notes),
+            Voicing::Drop2 => drop(notes, &[2]),
+            Voicing::Drop3 => drop(notes, &[3]),
+            Voicing::Drop2And4 => drop(notes, &[2, 4]),
+            Voicing::Quartal => quartal(notes),
+            Voicing::Shell => {
+                let intervals: Vec<u8> = notes.iter().map(|n| interval(root, *n)).collect();
+                let has = |wanted: &[u8]| intervals.iter().any(|i| wanted.contains(i));
+                // fall back to the suspended tone or the fifth when there's no third or seventh
+                let third: &[u8] = if has(&[3, 4]) { &[3, 4] } else { &[2, 5] };
+                let seventh: &[u8] = if has(&[9, 10, 11]) {
+                    &[9, 10, 11]
+                } else {
+                    &[6, 7, 8]
+                };
+                notes.retain(|n| {
+                    let i = interval(root, *n);
+                    i == 0 || third.contains(&i) || seventh.contains(&i)
+                });
+            }
+            Voicing::Rootless => {
+                // keep the root if it's the only tone
+                if notes.iter().any(|n| interval(root, *n) != 0) {
+                    notes.retain(|n| interval(root, *n) != 0);
+                }
+            }
+            Voicing::Spread => {
+                open(notes);
+                if let Some(bass) = notes.iter_mut().find(|n| interval(root, **n) == 0)
+                    && let Ok(lowered) = bass.step(-12)
+                {
+                    *bass = lowered;
+                }
+            }
+        }
+        notes.sort();
+        notes.dedup();
+    }
+}
+
+/// Semitones from the root's pitch class up to the note's pitch class
+fn interval(root: Note, note: Note) -> u8 {
+    (u8::from(note) + 12 - u8::from(root) % 12) % 12
+}
+
+/// Raise every second note, starting from the second lowest, an octave
+fn open(notes: &mut [Note]) {
+    notes.sort();
+    for note in notes.iter_mut().skip(1).step_by(2) {
+        if let Ok(raised) = note.step(12) {
+            *note = raised;
+        }
+    }
+}
+
+/// Reorder the chord's pitch classes so each is as close as possible to a perfect fourth above
+/// the last, starting near the lowest note
+fn quartal(notes: &mut Vec<Note>) {
+    let Some(&lowest) = notes.iter().min() else {
+        return;
+    };
+    let mut pitch_classes: Vec<u8> = notes.iter().map(|n| u8::from(*n) % 12).collect();
+    pitch_classes.sort();
+    pitch_classes.dedup();
+
+    // try every tone at the bottom, keeping the order whose steps stray least from fourths
+    let mut best: Option<(u8, u8, Vec<u8>)> = None;
+    for &first in &pitch_classes {
+        let mut last = first;
+        let mut steps = vec![];
+        let mut remaining: Vec<u8> = pitch_classes
+            .iter()
+            .copied()
+            .filter(|&p| p != first)
+            .collect();
+        while !remaining.is_empty() {
+            let step = |p: u8| (p + 12 - last) % 12;
+            let (index, _) = remaining
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, p)| step(**p).abs_diff(5))
+                .unwrap();
+            let next = remaining.remove(index);
+            steps.push(step(next));
+            last = next;
+        }
+        let cost = steps.iter().map(|s| s.abs_diff(5)).sum();
+        if best
+            .as_ref()
+            .is_none_or(|(best_cost, _, _)| cost < *best_cost)
+        {
+            best = Some((cost, first, steps));
+        }
+    }
+    let Some((_, first, steps)) = best else {
+        return;
+    };
+
+    // start on the first tone nearest the chord's lowest note
+    let start = ((first + 12 - u8::from(lowest) % 12) % 12) as i8;
+    let mut note = lowest.step(if start > 6 { start - 12 } else { start });
+    let mut voiced = vec![];
+    for step in [0].into_iter().chain(steps) {
+        note = note.and_then(|n| n.step(step as i8));
+        match note {
+            Ok(n) => voiced.push(n),
+            // leave the chord as it is rather than losing tones beyond the MIDI range
+            Err(_) => return,
+        }
+    }
+    *notes = voiced;
+}
+
+/// Drop the notes at the given positions, counted from the highest note, an octave
+fn drop(notes: &mut [Note], positions: &[usize]) {
+    notes.sort();
+    let count = notes.len();
+    for &position in positions {
+        if position <= count
+            && let Ok(lowered) = notes[count - position].step(-12)
+        {
+            notes[count - position] = lowered;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn notes(values: &[u8]) -> Vec<Note> {
+        values.iter().map(|&v| Note::from_u8_lossy(v)).collect()
+    }
+
+    fn pitch_classes(notes: &[Note]) -> Vec<u8> {
+        let mut classes: Vec<u8> = notes.iter().map(|n| u8::from(*n) % 12).collect();
+        classes.sort();
+        classes.dedup();
+        classes
+    }
+
+    /// C, Cmaj7, Cm9, C6/9, C13 and Cm7 in second inversion
+    fn chords() -> Vec<Vec<Note>> {
+        vec![
+            notes(&[60, 64, 67]),
+            notes(&[60, 64, 67, 71]),
+            notes(&[60, 63, 67, 70, 74]),
+            notes(&[60, 62, 64, 67, 69]),
+            notes(&[60, 64, 67, 70, 74, 81]),
+            notes(&[67, 70, 72, 75]),
+        ]
+    }
+
+    #[test]
+    fn voicings_keep_the_chord() {
+        for voicing in Voicing::ALL {
+            for chord in chords() {
+                let mut voiced = chord.clone();
+                voicing.apply(&mut voiced, Note::C4);
+                assert!(voiced.windows(2).all(|pair| pair[0] < pair[1]));
+                let kept = pitch_classes(&voiced);
+                match voicing {
+                    // these leave tones out on purpose
+                    Voicing::Shell | Voicing::Rootless => {
+                        assert!(kept.iter().all(|p| pitch_classes(&chord).contains(p)));
+                    }
+                    _ => assert_eq!(kept, pitch_classes(&chord), "{:?} {:?}", voicing, chord),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn shell_and_rootless_drop_their_tones() {
+        let mut shell = notes(&[60, 63, 67, 70, 74]);
+        Voicing::Shell.apply(&mut shell, Note::C4);
+        assert_eq!(pitch_classes(&shell), [0, 3, 10]);
+        let mut rootless = notes(&[60, 64, 67, 71]);
+        Voicing::Rootless.apply(&mut rootless, Note::C4);
+        assert_eq!(pitch_classes(&rootless), [4, 7, 11]);
+    }
+
+    #[test]
+    fn quartal_stacks_the_chords_own_tones() {
+        // C6/9 as E A D G C
+        let mut six_nine = notes(&[60, 62, 64, 67, 69]);
+        Voicing::Quartal.apply(&mut six_nine, Note::C4);
+        assert_eq!(six_nine, notes(&[64, 69, 74, 79, 84]));
+        // Cmaj7 as B E G C, its closest to fourths, starting just below the root
+        let mut major_seventh = notes(&[60, 64, 67, 71]);
+        Voicing::Quartal.apply(&mut major_seventh, Note::C4);
+        assert_eq!(major_seventh, notes(&[59, 64, 67, 72]));
+    }
+}